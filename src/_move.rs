@@ -12,6 +12,10 @@ impl Move {
     // Note that the null move is invalid because the from and to squares are the same
     pub const NULL: Move = Move { data: 0 };
 
+    /// The raw representation, for storing moves compactly
+    pub fn bits(&self) -> u32 { self.data }
+    pub fn from_bits(data: u32) -> Self { Move { data: data } }
+
     pub fn from(&self)  -> u32 { self.data & 0x3F }
     pub fn to(&self)    -> u32 { (self.data >> 6) & 0x3F }
     pub fn flags(&self) -> u32 { self.data >> 12 }
@@ -1,6 +1,4 @@
-use std::cmp::Ordering::{Less, Greater};
-use std::cmp::max;
-use itertools::Itertools;
+use std::cmp::{min, max};
 use types::*;
 use util::*;
 use _move::*;
@@ -8,16 +6,29 @@ use table::Hash;
 use bitboard::BitBoard;
 use print::*;
 use magics::*;
+use perft;
 
 pub const START_FEN: &'static str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
 
 pub type Squares = [u8; 64];
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GameState {
+    Ongoing, Checkmate, Stalemate, FiftyMoves, Repetition
+}
+
+/// The moves to generate. Captures include every promotion, and quiets include castling
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MoveType {
+    Captures, Quiets, All
+}
+
 #[derive(Copy)]
 pub struct Board {
     pub bb: BitBoard,
     pub sqs: Squares,
     pub ply: usize,
+    pub halfmove: usize,
     pub to_move: u8,
     pub hash: Hash,
     pub castling: u8,
@@ -28,68 +39,141 @@ impl Clone for Board { fn clone(&self) -> Self { *self } }
 
 impl Board {
     pub fn start_position() -> Self {
-        Board::from_fen(&mut START_FEN.split_whitespace())
+        Board::from_fen(&mut START_FEN.split_whitespace()).unwrap()
     }
 
-    pub fn from_fen(fen: &mut Params) -> Self {
-        let fen_board = fen.next().expect("Missing fen board");
-        let reversed_rows = fen_board.split('/').rev(); // fen is read from top row
+    pub fn from_fen(fen: &mut Params) -> Result<Self, ParseError> {
+        let fen_board = try!(fen.next().ok_or(ParseError::Missing("fen board")));
+        let invalid_board = || ParseError::InvalidBoard(fen_board.into());
+
+        let rows: Vec<&str> = fen_board.split('/').collect();
+        if rows.len() != 8 { return Err(invalid_board()) }
 
         let mut sqs = [EMPTY; 64];
 
-        for (r, row) in reversed_rows.enumerate() {
-            let mut offset = 0;
-            for (c, ch) in row.chars().enumerate() {
-                if !ch.is_numeric() {
-                    sqs[r*8 + c+offset] = to_piece(ch);
+        // Fen is read from the top row
+        for (r, row) in rows.iter().rev().enumerate() {
+            let mut c = 0;
+            for ch in row.chars() {
+                if let Some(skip) = ch.to_digit(10) {
+                    c += skip as usize;
                 } else {
-                    offset += (ch as u8 - b'1') as usize;
+                    let piece = to_piece(ch);
+                    if piece == EMPTY || c >= 8 { return Err(invalid_board()) }
+                    sqs[r*8 + c] = piece;
+                    c += 1;
                 }
             }
+            if c != 8 { return Err(invalid_board()) }
         }
 
-        let to_move = match fen.next().expect("[w, b]") {
+        for &king in &[KING | WHITE, KING | BLACK] {
+            if sqs.iter().filter(|&&sq| sq == king).count() != 1 { return Err(invalid_board()) }
+        }
+
+        let to_move = match try!(fen.next().ok_or(ParseError::Missing("side to move"))) {
             "w" => WHITE,
-            _   => BLACK,
+            "b" => BLACK,
+            side => return Err(ParseError::InvalidSide(side.into()))
         };
 
-        let castle_str = fen.next().expect("Castling [KQkq]");
+        let castle_str = try!(fen.next().ok_or(ParseError::Missing("castling rights")));
+        // Each right needs its king and rook to be on their starting squares
+        let rights = [('K', WK_CASTLE, WHITE, 4, 7), ('Q', WQ_CASTLE, WHITE, 4, 0),
+                      ('k', BK_CASTLE, BLACK, 60, 63), ('q', BQ_CASTLE, BLACK, 60, 56)];
         let mut castling = 0;
-        if castle_str.contains('K') { castling |= WK_CASTLE }
-        if castle_str.contains('Q') { castling |= WQ_CASTLE }
-        if castle_str.contains('k') { castling |= BK_CASTLE }
-        if castle_str.contains('q') { castling |= BQ_CASTLE }
 
-        let ep_sq: Vec<char> = fen.next().expect("En Passant target square").chars().collect();
+        for ch in castle_str.chars().filter(|&ch| ch != '-') {
+            match rights.iter().find(|right| right.0 == ch) {
+                Some(&(_, right, color, king, rook))
+                    if sqs[king] == KING | color && sqs[rook] == ROOK | color => castling |= right,
+                _ => return Err(ParseError::InvalidCastling(castle_str.into()))
+            }
+        }
+
+        let ep_str = try!(fen.next().ok_or(ParseError::Missing("en passant square")));
+        let ep_sq: Vec<char> = ep_str.chars().collect();
 
         let en_passant = match ep_sq[..] {
             ['-'] => 0,
-            [sc @ 'a'...'h', sr @ '3', '6'] => 1 << to_pos(sc, sr),
-            _ => panic!("Invalid en passant token")
+            [sc @ 'a'...'h', sr @ '3'] | [sc @ 'a'...'h', sr @ '6'] => 1 << to_pos(sc, sr),
+            _ => return Err(ParseError::InvalidEnPassant(ep_str.into()))
+        };
+
+        // The move counters may be left out, such as before the moves of a position command
+        let mut counter = |def| match fen.clone().next() {
+            None | Some("moves") => Ok(def),
+            word => { fen.next(); parse(word) }
         };
+        let halfmove = try!(counter(0));
+        let fullmove: usize = try!(counter(1));
+        let ply = 2 * (max(fullmove, 1) - 1) + if to_move == BLACK { 1 } else { 0 };
 
         let bitboard = BitBoard::generate_from(&sqs);
         let hash = Hash::init(&sqs, castling, en_passant, to_move);
 
-        Board { bb: bitboard, sqs: sqs, ply: 0, to_move: to_move,
-                hash: hash, castling: castling, en_passant: en_passant }
+        let board = Board { bb: bitboard, sqs: sqs, ply: ply, halfmove: halfmove, to_move: to_move,
+                            hash: hash, castling: castling, en_passant: en_passant };
+
+        // The side which has just moved can't be left in check
+        if board.player_in_check(board.prev_move()) { return Err(invalid_board()) }
+        Ok(board)
     }
 
-    pub fn perft(&self, depth: u8, print: bool) -> usize {
-        if self.player_in_check(self.prev_move()) { return 0 }
+    /// Write the position as FEN, which `from_fen` reads back to the same board
+    pub fn to_fen(&self) -> String {
+        let rows: Vec<String> = (0..8).rev().map(|r| {
+            let mut row = String::new();
+            let mut empty = 0;
 
-        if depth == 0 { return 1 }
+            for &sq in &self.sqs[r*8..r*8 + 8] {
+                if sq == EMPTY {
+                    empty += 1;
+                    continue
+                }
+                if empty > 0 { row.push_str(&empty.to_string()) }
+                empty = 0;
+                row.push(to_char(sq));
+            }
+            if empty > 0 { row.push_str(&empty.to_string()) }
+            row
+        }).collect();
+
+        let to_move = if self.to_move == WHITE { "w" } else { "b" };
+
+        let rights = [(WK_CASTLE, 'K'), (WQ_CASTLE, 'Q'), (BK_CASTLE, 'k'), (BQ_CASTLE, 'q')];
+        let mut castling: String = rights.iter().filter(|&&(right, _)| self.castling & right != 0)
+                                         .map(|&(_, ch)| ch).collect();
+        if castling.is_empty() { castling.push('-') }
+
+        let en_passant = match self.en_passant {
+            0 => "-".to_string(),
+            _ => {
+                let (col, row) = from_pos(lsb(self.en_passant));
+                format!("{}{}", col, row)
+            }
+        };
 
-        let mut count = 0;
-        for mv in self.get_moves() {
-            let mut new_board = *self;
-            new_board.make_move(mv);
+        format!("{} {} {} {} {} {}", rows.join("/"), to_move, castling, en_passant,
+                self.halfmove, self.fullmove())
+    }
+
+    /// The number of the move being played, which starts at 1 and increases after black moves
+    pub fn fullmove(&self) -> usize {
+        self.ply / 2 + 1
+    }
+
+    /// Count the leaves of the move tree to a depth, printing the count below each move if asked
+    pub fn perft(&self, depth: u8, print: bool) -> usize {
+        if depth == 0 { return 1 }
 
-            let n = new_board.perft(depth - 1, false);
-            if print && n > 0 { println!("{}: {}", mv, n) }
-            count += n;
+        let counts = perft::divide(self, depth, 1, None);
+        if print {
+            for &(mv, count) in &counts {
+                println!("{}: {}", mv, count);
+            }
         }
-        count
+        counts.iter().map(|&(_, count)| count).sum()
     }
 
     pub fn do_null_move(&mut self) {
@@ -132,6 +216,7 @@ impl Board {
         let opp = flip(color);
         let offset = Board::color_offset(color);
 
+        self.halfmove = if self.is_irreversible(mv) { 0 } else { self.halfmove + 1 };
         self.do_null_move();
 
         let dest_piece = match mv.promotion() {
@@ -192,40 +277,159 @@ impl Board {
         if us == WHITE { 0 } else { 56 }
     }
 
-    pub fn move_from_str(&mut self, mv: &str) -> Move {
-        let moves: Vec<char> = mv.chars().collect();
+    /// Find the legal move written in coordinate notation, such as e2e4 or e7e8q
+    pub fn move_from_str(&self, mv: &str) -> Result<Move, ParseError> {
+        let chars: Vec<char> = mv.chars().collect();
+
+        let (src, dest, promotion) = match chars[..] {
+            [sc @ 'a'...'h', sr @ '1'...'8', dc @ 'a'...'h', dr @ '1'...'8', ref promotion..] => {
+                let promotion = match promotion {
+                    &[]    => 0,
+                    &['q'] => QUEEN_PROM,
+                    &['r'] => ROOK_PROM,
+                    &['b'] => BISHOP_PROM,
+                    &['n'] => KNIGHT_PROM,
+                    _ => return Err(ParseError::InvalidMove(mv.into()))
+                };
+                (to_pos(sc, sr), to_pos(dc, dr), promotion)
+            },
+            _ => return Err(ParseError::InvalidMove(mv.into()))
+        };
 
-        if let [sc, sr, dc, dr, ref promotion..] = moves[..] {
-            let (src, dest) = (to_pos(sc, sr), to_pos(dc, dr));
+        self.legal_moves().into_iter()
+            .find(|m| m.from() == src && m.to() == dest && m.promotion() == promotion)
+            .ok_or(ParseError::IllegalMove(mv.into()))
+    }
 
-            let mut flags = match promotion {
-                &['q'] => QUEEN_PROM,
-                &['r'] => ROOK_PROM,
-                &['b'] => BISHOP_PROM,
-                &['n'] => KNIGHT_PROM,
-                _ => 0
-            };
+    /// Write a legal move in Standard Algebraic Notation, such as Nbd7, exd6, e8=Q+ or O-O-O#
+    pub fn to_san(&self, mv: Move) -> String {
+        let (src, dest) = (mv.from(), mv.to());
+        let piece = self.sqs[src as usize] & PIECE;
+        let (sc, sr) = from_pos(src);
+        let (dc, dr) = from_pos(dest);
+        let mut san = String::new();
 
-            flags |= match mv {
-                "e1g1" if self.castling & WK_CASTLE != 0 => CASTLES_KING,
-                "e8g8" if self.castling & BK_CASTLE != 0 => CASTLES_KING,
-                "e1c1" if self.castling & WQ_CASTLE != 0 => CASTLES_QUEEN,
-                "e8c8" if self.castling & BQ_CASTLE != 0 => CASTLES_QUEEN,
-                _ => 0
-            };
+        if mv.king_castle() {
+            san.push_str("O-O");
+        } else if mv.queen_castle() {
+            san.push_str("O-O-O");
+        } else if piece == PAWN {
+            if mv.is_capture() {
+                san.push(sc);
+                san.push('x');
+            }
+            san.push(dc);
+            san.push(dr);
 
-            if self.sqs[src as usize] & PIECE == PAWN {
-                let is_double = if src > dest { src-dest } else { dest-src } == 16;
-                if is_double { flags |= DOUBLE_PAWN_PUSH };
+            if mv.promotion() != 0 {
+                san.push('=');
+                san.push(prom_char(mv.promotion()));
+            }
+        } else {
+            san.push(to_char(piece | WHITE));
+
+            // Only add the file or rank of the origin if another piece of the same type can move there
+            let others: Vec<(char, char)> = self.legal_moves().into_iter()
+                .filter(|m| m.to() == dest && m.from() != src && self.sqs[m.from() as usize] & PIECE == piece)
+                .map(|m| from_pos(m.from()))
+                .collect();
+
+            if !others.is_empty() {
+                if others.iter().all(|&(c, _)| c != sc) {
+                    san.push(sc);
+                } else if others.iter().all(|&(_, r)| r != sr) {
+                    san.push(sr);
+                } else {
+                    san.push(sc);
+                    san.push(sr);
+                }
+            }
+
+            if mv.is_capture() { san.push('x') }
+            san.push(dc);
+            san.push(dr);
+        }
+
+        let mut new_board = *self;
+        new_board.make_move(mv);
+        if new_board.is_in_check() {
+            san.push(if new_board.has_legal_move() { '+' } else { '#' });
+        }
+        san
+    }
+
+    /// Find the legal move written in Standard Algebraic Notation. Common variations are also
+    /// accepted, such as annotations, 0-0 for castling, a missing or lower case promotion piece,
+    /// a missing `x`, extra disambiguation, and coordinate notation
+    pub fn parse_san(&self, san: &str) -> Result<Move, ParseError> {
+        let trimmed = san.trim_right_matches(|c| "+#!?".contains(c)).trim_right_matches("e.p.");
+
+        let castle = match trimmed {
+            "O-O" | "0-0" | "o-o"       => Some(CASTLES_KING),
+            "O-O-O" | "0-0-0" | "o-o-o" => Some(CASTLES_QUEEN),
+            _ => None
+        };
+
+        if let Some(side) = castle {
+            return self.legal_moves().into_iter().find(|mv| mv.flags() & side != 0)
+                       .ok_or(ParseError::IllegalMove(san.into()))
+        }
+
+        if let Ok(mv) = self.move_from_str(trimmed) { return Ok(mv) }
+
+        let invalid = || ParseError::InvalidMove(san.into());
+        let mut chars: Vec<char> = trimmed.chars().filter(|&c| c != 'x' && c != '-' && c != '=' && c != ':').collect();
+
+        let promotion = match chars.last().map(|c| c.to_ascii_uppercase()) {
+            Some('Q') => QUEEN_PROM,
+            Some('R') => ROOK_PROM,
+            Some('B') => BISHOP_PROM,
+            Some('N') => KNIGHT_PROM,
+            _ => 0
+        };
+        if promotion != 0 { chars.pop(); }
 
-                let is_en_passant = dest == lsb(self.en_passant);
-                if is_en_passant { flags |= EN_PASSANT };
+        if chars.len() < 2 { return Err(invalid()) }
+        let dest = match chars.split_off(chars.len() - 2)[..] {
+            [dc @ 'a'...'h', dr @ '1'...'8'] => to_pos(dc, dr),
+            _ => return Err(invalid())
+        };
+
+        // A lower case b is a pawn on the b file, unless only a bishop move fits
+        let pieces = match chars.first() {
+            Some(&'b') => vec![PAWN, BISHOP],
+            Some(&c) if "NBRQK".contains(c) || "nrqk".contains(c) => vec![to_piece(c) & PIECE],
+            _ => vec![PAWN]
+        };
+
+        for &piece in &pieces {
+            let origin = if piece == PAWN { &chars[..] } else { &chars[1..] };
+            let (mut file, mut rank) = (None, None);
+
+            for &c in origin {
+                match c {
+                    'a'...'h' if file.is_none() => file = Some(c),
+                    '1'...'8' if rank.is_none() => rank = Some(c),
+                    _ => return Err(invalid())
+                }
             }
 
-            Move::new(src, dest, flags)
-        } else {
-            panic!("Malformed move {}", mv)
+            let candidates: Vec<Move> = self.legal_moves().into_iter().filter(|mv| {
+                let (sc, sr) = from_pos(mv.from());
+                mv.to() == dest && self.sqs[mv.from() as usize] & PIECE == piece
+                && !mv.king_castle() && !mv.queen_castle()
+                && file.map_or(true, |f| f == sc) && rank.map_or(true, |r| r == sr)
+                // A promotion without a piece is taken to be to a queen
+                && (mv.promotion() == promotion || promotion == 0 && mv.promotion() == QUEEN_PROM)
+            }).collect();
+
+            match candidates.len() {
+                0 => continue,
+                1 => return Ok(candidates[0]),
+                _ => return Err(ParseError::AmbiguousMove(san.into()))
+            }
         }
+        Err(ParseError::IllegalMove(san.into()))
     }
 
     pub fn see(&mut self, pos: u32, us: u8) -> i32 {
@@ -249,11 +453,8 @@ impl Board {
         p_val(captured) as i32 - clone.see(mv.to(), self.to_move)
     }
 
-    /// Return the lowest valued enemy attacker of a given square and the attackers position
-    pub fn attacker(&self, pos: u32, us: u8) -> (u8, u32) {
-        let bb = &self.bb;
-        let opp = flip(us);
-
+    /// The pawns of a side which attack a given square
+    fn pawn_attackers(&self, pos: u32, opp: u8) -> u64 {
         let l_file = if pos % 8 > 0 { file(pos - 1) } else { 0 };
         let r_file = if pos % 8 < 7 { file(pos + 1) } else { 0 };
         let row_n = pos / 8;
@@ -263,7 +464,15 @@ impl Board {
             _ => 0
         };
 
-        let pawns = bb[PAWN | opp] & attacking_row & (l_file | r_file);
+        self.bb[PAWN | opp] & attacking_row & (l_file | r_file)
+    }
+
+    /// Return the lowest valued enemy attacker of a given square and the attackers position
+    pub fn attacker(&self, pos: u32, us: u8) -> (u8, u32) {
+        let bb = &self.bb;
+        let opp = flip(us);
+
+        let pawns = self.pawn_attackers(pos, opp);
         if pawns != 0 { return (PAWN | opp, lsb(pawns)) }
 
         let knights = knight_moves(pos) & bb[KNIGHT | opp];
@@ -286,48 +495,93 @@ impl Board {
         (EMPTY, !0)
     }
 
+    /// Whether a pawn on a square would have no enemy pawns ahead of it, on its own file or
+    /// either adjacent file
+    pub fn is_passed_pawn(&self, pos: u32, us: u8) -> bool {
+        let l_file = if pos % 8 > 0 { file(pos - 1) } else { 0 };
+        let r_file = if pos % 8 < 7 { file(pos + 1) } else { 0 };
+        let row_n = pos / 8;
+        let ahead = if us == WHITE { !0 << 8 * row_n << 8 } else { (1 << 8 * row_n) - 1 };
+
+        self.bb[PAWN | flip(us)] & ahead & (l_file | file(pos) | r_file) == 0
+    }
+
+    /// Every enemy piece which attacks a given square
+    pub fn attackers(&self, pos: u32, us: u8) -> u64 {
+        self.attackers_with(pos, us, self.bb[ALL | WHITE] | self.bb[ALL | BLACK])
+    }
+
+    /// Every enemy piece which would attack a square, if the board had a different occupancy
+    fn attackers_with(&self, pos: u32, us: u8, occ: u64) -> u64 {
+        let bb = &self.bb;
+        let opp = flip(us);
+
+          self.pawn_attackers(pos, opp)
+        | knight_moves(pos) & bb[KNIGHT | opp]
+        | bishop_moves(pos, occ) & (bb[BISHOP | opp] | bb[QUEEN | opp])
+        | rook_moves(pos, occ) & (bb[ROOK | opp] | bb[QUEEN | opp])
+        | king_moves(pos) & bb[KING | opp]
+    }
+
+    /// The pieces giving check to the side to move
+    pub fn checkers(&self) -> u64 {
+        self.attackers(lsb(self.bb[KING | self.to_move]), self.to_move)
+    }
+
+    /// The pseudo legal moves, which may leave the king in check
     pub fn get_moves(&self) -> Vec<Move> {
+        self.generate(!0, !0, MoveType::All)
+    }
+
+    /// Generate the pseudo legal moves of one type for some of our pieces. Only the king,
+    /// and en passant captures, may move to squares other than the targets
+    fn generate(&self, pieces: u64, targets: u64, kind: MoveType) -> Vec<Move> {
         let bb = &self.bb;
         let mut moves: Vec<Move> = Vec::with_capacity(64);
 
         let (us, opp) = (self.to_move, self.prev_move());
         let enemies = bb[ALL | opp];
         let occ = bb[ALL | us] | enemies;
+        let quiet_targets = if kind != MoveType::Captures { !occ } else { 0 };
+        let capture_targets = if kind != MoveType::Quiets { enemies } else { 0 };
 
         let (row_3, row_8, l_file, r_file, up, left, right) =
             if us == WHITE { PAWN_INFO_WHITE } else { PAWN_INFO_BLACK };
 
-        for_all(bb[QUEEN | us], &mut |from| {
-            let mvs = queen_moves(from, occ);
-            add_moves_from(&mut moves, from, mvs & !occ, 0);
-            add_moves_from(&mut moves, from, mvs & enemies, IS_CAPTURE);
+        for_all(bb[QUEEN | us] & pieces, &mut |from| {
+            let mvs = queen_moves(from, occ) & targets;
+            add_moves_from(&mut moves, from, mvs & quiet_targets, 0);
+            add_moves_from(&mut moves, from, mvs & capture_targets, IS_CAPTURE);
         });
 
-        for_all(bb[ROOK | us], &mut |from| {
-            let mvs = rook_moves(from, occ);
-            add_moves_from(&mut moves, from, mvs & !occ, 0);
-            add_moves_from(&mut moves, from, mvs & enemies, IS_CAPTURE);
+        for_all(bb[ROOK | us] & pieces, &mut |from| {
+            let mvs = rook_moves(from, occ) & targets;
+            add_moves_from(&mut moves, from, mvs & quiet_targets, 0);
+            add_moves_from(&mut moves, from, mvs & capture_targets, IS_CAPTURE);
         });
 
-        for_all(bb[BISHOP | us], &mut |from| {
-            let mvs = bishop_moves(from, occ);
-            add_moves_from(&mut moves, from, mvs & !occ, 0);
-            add_moves_from(&mut moves, from, mvs & enemies, IS_CAPTURE);
+        for_all(bb[BISHOP | us] & pieces, &mut |from| {
+            let mvs = bishop_moves(from, occ) & targets;
+            add_moves_from(&mut moves, from, mvs & quiet_targets, 0);
+            add_moves_from(&mut moves, from, mvs & capture_targets, IS_CAPTURE);
         });
 
-        for_all(bb[KNIGHT | us], &mut |from| {
-            let mvs = knight_moves(from);
-            add_moves_from(&mut moves, from, mvs & !occ, 0);
-            add_moves_from(&mut moves, from, mvs & enemies, IS_CAPTURE);
+        for_all(bb[KNIGHT | us] & pieces, &mut |from| {
+            let mvs = knight_moves(from) & targets;
+            add_moves_from(&mut moves, from, mvs & quiet_targets, 0);
+            add_moves_from(&mut moves, from, mvs & capture_targets, IS_CAPTURE);
         });
 
-        let from = lsb(bb[KING | us]);
-        let mvs = king_moves(from);
-        add_moves_from(&mut moves, from, mvs & !occ, 0);
-        add_moves_from(&mut moves, from, mvs & enemies, IS_CAPTURE);
+        let king = bb[KING | us] & pieces;
+        if king != 0 {
+            let from = lsb(king);
+            let mvs = king_moves(from);
+            add_moves_from(&mut moves, from, mvs & quiet_targets, 0);
+            add_moves_from(&mut moves, from, mvs & capture_targets, IS_CAPTURE);
+        }
 
-        let (pushes, double_pushes, left_attacks, right_attacks);
-        let pawns = bb[PAWN | us];
+        let (mut pushes, mut double_pushes, mut left_attacks, mut right_attacks);
+        let pawns = bb[PAWN | us] & pieces;
 
         if us == WHITE {
             pushes = (pawns << up) & !occ;
@@ -341,25 +595,38 @@ impl Board {
             right_attacks = (pawns >> -right) & (enemies | self.en_passant) & !l_file;
         }
 
+        pushes &= targets;
+        double_pushes &= targets;
+        left_attacks &= targets | self.en_passant;
+        right_attacks &= targets | self.en_passant;
+
         let l_en_passant = left_attacks & self.en_passant;
         let r_en_passant = right_attacks & self.en_passant;
         let prom_pushes = pushes & row_8;
         let prom_l_att = left_attacks & row_8;
         let prom_r_att = right_attacks & row_8;
 
-        add_moves(&mut moves, pushes ^ prom_pushes, up, 0);
-        add_moves(&mut moves, double_pushes, up+up, DOUBLE_PAWN_PUSH);
-        add_moves(&mut moves, left_attacks ^ l_en_passant ^ prom_l_att, left, IS_CAPTURE);
-        add_moves(&mut moves, right_attacks ^ r_en_passant ^ prom_r_att, right, IS_CAPTURE);
-        add_moves(&mut moves, l_en_passant, left, EN_PASSANT | IS_CAPTURE);
-        add_moves(&mut moves, r_en_passant, right, EN_PASSANT | IS_CAPTURE);
-        add_prom_moves(&mut moves, prom_pushes, up, 0);
-        add_prom_moves(&mut moves, prom_l_att, left, IS_CAPTURE);
-        add_prom_moves(&mut moves, prom_r_att, right, IS_CAPTURE);
+        if kind != MoveType::Captures {
+            add_moves(&mut moves, pushes ^ prom_pushes, up, 0);
+            add_moves(&mut moves, double_pushes, up+up, DOUBLE_PAWN_PUSH);
+        }
+
+        // Promotions are generated with the captures, as they also change the material
+        if kind != MoveType::Quiets {
+            add_moves(&mut moves, left_attacks ^ l_en_passant ^ prom_l_att, left, IS_CAPTURE);
+            add_moves(&mut moves, right_attacks ^ r_en_passant ^ prom_r_att, right, IS_CAPTURE);
+            add_moves(&mut moves, l_en_passant, left, EN_PASSANT | IS_CAPTURE);
+            add_moves(&mut moves, r_en_passant, right, EN_PASSANT | IS_CAPTURE);
+            add_prom_moves(&mut moves, prom_pushes, up, 0);
+            add_prom_moves(&mut moves, prom_l_att, left, IS_CAPTURE);
+            add_prom_moves(&mut moves, prom_r_att, right, IS_CAPTURE);
+        }
 
         let offset = Board::color_offset(us);
 
-        if    self.castling & (BK_CASTLE << us) != 0
+        if    king != 0
+           && kind != MoveType::Captures
+           && self.castling & (BK_CASTLE << us) != 0
            && self.sqs[offset + 5] == EMPTY
            && self.sqs[offset + 6] == EMPTY
            && self.attacker(offset as u32 + 5, us).0 == EMPTY
@@ -369,7 +636,9 @@ impl Board {
             add_moves(&mut moves, 1 << (offset + 6), 2, CASTLES_KING);
         }
 
-        if    self.castling & (BQ_CASTLE << us) != 0
+        if    king != 0
+           && kind != MoveType::Captures
+           && self.castling & (BQ_CASTLE << us) != 0
            && self.sqs[offset + 3] == EMPTY
            && self.sqs[offset + 2] == EMPTY
            && self.sqs[offset + 1] == EMPTY
@@ -383,40 +652,112 @@ impl Board {
         moves
     }
 
-    /// Move better SEE to the front to improve move ordering in alpha-beta search
-    pub fn qsort(&self, moves: &[Move]) -> Vec<(i32, Move)> {
-        moves.iter().map(
-            |mv| (self.see_move(mv), *mv))
-            .filter(|e| e.0 > 0)
-            .sorted_by(|a,b| if a.0 > b.0 { Less } else { Greater })
-    }
-
-    pub fn sort_with(&self, moves: Vec<Move>, best: Move, killer: &Killer) -> Vec<(i32, Move)> {
-        moves.iter().map(
-            |&mv| {
-                // Give the largest value to the best move to place it at the front
-                // Give killer moves values just above zero to put them ahead of
-                // all non-captures and behind all positive see moves
-                let see = if mv == best     { 10_000_000 }
-                     else if mv == killer.0 { 2 }
-                     else if mv == killer.1 { 1 }
-                     else { self.see_move(&mv) };
-
-                (see, mv)
-            })
-            .sorted_by(|a,b| if a.0 > b.0 { Less } else { Greater })
-    }
-
     pub fn player_in_check(&self, us: u8) -> bool {
         let king_pos = lsb(self.bb[KING | us]);
         self.attacker(king_pos, us).0 != EMPTY
     }
 
+    /// Captures and pawn moves reset the fifty move counter. Castling is also irreversible, but it
+    /// doesn't need to reset the counter, because losing the castling rights changes the hash
     pub fn is_irreversible(&self, mv: Move) -> bool {
            mv.is_capture()
         || self.sqs[mv.from() as usize] & PIECE == PAWN
-        || mv.king_castle()
-        || mv.queen_castle()
+    }
+
+    /// Our pieces which are pinned to the king by an enemy slider
+    pub fn pinned(&self) -> u64 {
+        let bb = &self.bb;
+        let (us, opp) = (self.to_move, self.prev_move());
+        let king = lsb(bb[KING | us]);
+        let occ = bb[ALL | us] | bb[ALL | opp];
+
+        let snipers = rook_moves(king, 0) & (bb[ROOK | opp] | bb[QUEEN | opp])
+                    | bishop_moves(king, 0) & (bb[BISHOP | opp] | bb[QUEEN | opp]);
+        let mut pinned = 0;
+
+        for_all(snipers, &mut |sniper| {
+            let blockers = between(king, sniper) & occ;
+            if count(blockers) == 1 { pinned |= blockers & bb[ALL | us] }
+        });
+        pinned
+    }
+
+    /// The squares that a piece other than the king must move to, in order to resolve any check.
+    /// These are the checker and the squares between it and the king, or none in double check
+    pub fn evasions(&self, checkers: u64) -> u64 {
+        match count(checkers) {
+            0 => !0,
+            1 => checkers | between(lsb(self.bb[KING | self.to_move]), lsb(checkers)),
+            _ => 0
+        }
+    }
+
+    /// Whether a pseudo legal move, which already resolves any check, leaves the king safe
+    fn is_safe(&self, mv: Move, pinned: u64) -> bool {
+        let us = self.to_move;
+        let king = lsb(self.bb[KING | us]);
+        let (from, to) = (mv.from(), mv.to());
+
+        if from == king {
+            // Castling has already checked every square the king crosses
+            if mv.king_castle() || mv.queen_castle() { return true }
+
+            // The king can't hide from a slider behind itself
+            let occ = (self.bb[ALL | WHITE] | self.bb[ALL | BLACK]) ^ 1 << king;
+            return self.attackers_with(to, us, occ) == 0
+        }
+
+        // En passant removes two pieces from a row, which is rare enough to check by making it
+        if mv.is_en_passant() {
+            let mut new_board = *self;
+            new_board.make_move(mv);
+            return !new_board.player_in_check(us)
+        }
+
+        // A pinned piece may only move along the line through it and the king
+        pinned & 1 << from == 0 || between(king, to) & 1 << from != 0 || between(king, from) & 1 << to != 0
+    }
+
+    /// The legal moves, found from the checkers and pinned pieces without making any move
+    pub fn legal_moves(&self) -> Vec<Move> {
+        self.legal_moves_of(MoveType::All)
+    }
+
+    /// The legal moves of one type, so that a search may generate the captures first
+    pub fn legal_moves_of(&self, kind: MoveType) -> Vec<Move> {
+        let pinned = self.pinned();
+        let evasions = self.evasions(self.checkers());
+
+        self.generate(!0, evasions, kind).into_iter().filter(|&mv| self.is_safe(mv, pinned)).collect()
+    }
+
+    /// Whether a move is legal, such as a move from the table or a killer found in another position
+    pub fn is_legal(&self, mv: Move) -> bool {
+        let from = mv.from();
+        if mv == Move::NULL || self.bb[ALL | self.to_move] & 1 << from == 0 { return false }
+
+        let evasions = self.evasions(self.checkers());
+        self.generate(1 << from, evasions, MoveType::All).contains(&mv) && self.is_safe(mv, self.pinned())
+    }
+
+    pub fn has_legal_move(&self) -> bool {
+        !self.legal_moves().is_empty()
+    }
+
+    /// Determine whether the game is over, given the hashes of every position in the game
+    /// up to and including this one
+    pub fn game_state(&self, history: &[Hash]) -> GameState {
+        if !self.has_legal_move() {
+            return if self.is_in_check() { GameState::Checkmate } else { GameState::Stalemate }
+        }
+
+        if self.halfmove >= 100 { return GameState::FiftyMoves }
+
+        let recent = &history[history.len() - min(history.len(), self.halfmove + 1)..];
+        let occurrences = recent.iter().rev().enumerate()
+                                .filter(|&(i, &hash)| i % 2 == 0 && hash == self.hash).count();
+
+        if occurrences >= 3 { GameState::Repetition } else { GameState::Ongoing }
     }
 
     pub fn is_in_check(&self) -> bool {
@@ -0,0 +1,115 @@
+//! The engine controller, which owns the searcher and the thread a search runs on
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+use types::*;
+use search::Searcher;
+use timer::Timer;
+use uci::{self, EngineSettings};
+
+/// Commands which only take effect once the search in progress has finished
+const QUEUED: [&'static str; 3] = ["position", "setoption", "ucinewgame"];
+
+pub struct Engine {
+    searcher: Option<Searcher>,
+    worker: Option<JoinHandle<Searcher>>,
+    pending: Vec<String>,
+    should_stop: Flag,
+    is_searching: Flag
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        let should_stop = Arc::new(AtomicBool::new(false));
+        let timer = Timer::default(should_stop.clone());
+
+        Engine {
+            searcher: Some(Searcher::new(EngineSettings::default(), timer)),
+            worker: None,
+            pending: Vec::new(),
+            should_stop: should_stop,
+            is_searching: Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    pub fn is_searching(&self) -> bool {
+        self.worker.is_some()
+    }
+
+    /// Run a command which needs the searcher. While a search is running,
+    /// state changing commands are queued and everything else is rejected
+    pub fn execute(&mut self, line: &str) {
+        self.reclaim();
+
+        match self.searcher {
+            Some(ref mut searcher) => uci::execute(searcher, line),
+            None => {
+                let first_word = line.split_whitespace().next().unwrap_or("");
+
+                if QUEUED.contains(&first_word) {
+                    self.pending.push(line.into());
+                } else {
+                    println!("info string Ignoring {} while searching", first_word);
+                }
+            }
+        }
+    }
+
+    /// Start searching the current position on a separate thread
+    pub fn go(&mut self, params: &mut Params) {
+        self.reclaim();
+
+        let mut searcher = match self.searcher.take() {
+            Some(searcher) => searcher,
+            None => {
+                println!("info string Already searching, send stop first");
+                return
+            }
+        };
+
+        if let Err(e) = searcher.timer.replace(params) {
+            println!("info string {}", e);
+            self.searcher = Some(searcher);
+            return
+        }
+
+        self.is_searching.store(true, Ordering::SeqCst);
+
+        let is_searching = self.is_searching.clone();
+        self.worker = Some(thread::spawn(move || {
+            // The search is over before the GUI hears of it, so that an immediate reply is accepted
+            let best = searcher.think();
+            is_searching.store(false, Ordering::SeqCst);
+            println!("bestmove {}", best);
+            searcher
+        }));
+    }
+
+    /// Stop the search in progress, if any, and wait for its best move
+    pub fn stop(&mut self) {
+        if self.is_searching() {
+            self.should_stop.store(true, Ordering::SeqCst);
+            self.join();
+        }
+    }
+
+    /// Take back the searcher if the search has finished on its own
+    fn reclaim(&mut self) {
+        if self.is_searching() && !self.is_searching.load(Ordering::SeqCst) {
+            self.join();
+        }
+    }
+
+    /// Wait for the search thread, then apply all commands which arrived in the meantime
+    fn join(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            let mut searcher = worker.join().expect("The search thread panicked");
+
+            for line in self.pending.drain(..) {
+                uci::execute(&mut searcher, &line);
+            }
+            self.searcher = Some(searcher);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Extended Position Description records, and running test suites made of them
+use types::*;
+use util::*;
+use board::Board;
+use _move::Move;
+use search::Searcher;
+use uci;
+
+/// The limits each position of a test suite is searched with, when none are given
+const DEFAULT_LIMITS: &'static str = "movetime 1000";
+
+/// A position with its operations, such as bm Qd1+; id "WAC.001"; or D1 20;
+pub struct Epd {
+    pub board: Board,
+    /// Each opcode with its operands, in order, with any quotes removed
+    pub ops: Vec<(String, Vec<String>)>
+}
+
+impl Epd {
+    pub fn parse(line: &str) -> Result<Self, ParseError> {
+        let mut rest = line.trim();
+        let mut fields = Vec::new();
+
+        // The position is the first four fields of a FEN, although the move counters are often
+        // given as well. Otherwise they may be set by the hmvc and fmvn operations
+        while fields.len() < 6 {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let field = &rest[..end];
+            if field.is_empty() || fields.len() >= 4 && field.parse::<usize>().is_err() { break }
+
+            fields.push(field);
+            rest = rest[end..].trim_left();
+        }
+
+        let ops: Vec<(String, Vec<String>)> = split_ops(rest).iter()
+            .map(|op| split_words(op))
+            .filter(|words| !words.is_empty())
+            .map(|mut words| {
+                let operands = words.split_off(1);
+                (words.pop().unwrap(), operands)
+            }).collect();
+
+        let counter = |opcode, def: &str| ops.iter().find(|&&(ref op, _)| op == opcode)
+            .and_then(|&(_, ref operands)| operands.first().cloned())
+            .unwrap_or(def.into());
+
+        let mut fen = fields.join(" ");
+        if fields.len() == 4 {
+            fen = format!("{} {} {}", fen, counter("hmvc", "0"), counter("fmvn", "1"));
+        }
+
+        let board = try!(Board::from_fen(&mut fen.split_whitespace()));
+        Ok(Epd { board: board, ops: ops })
+    }
+
+    pub fn operands(&self, opcode: &str) -> Option<&[String]> {
+        self.ops.iter().find(|&&(ref op, _)| op == opcode).map(|&(_, ref operands)| &operands[..])
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.operands("id").and_then(|operands| operands.first()).map(|id| &id[..])
+    }
+
+    /// The moves of an operation such as bm or am, which are usually given in SAN
+    pub fn moves(&self, opcode: &str) -> Result<Vec<Move>, ParseError> {
+        let mut moves = Vec::new();
+        for mv in self.operands(opcode).unwrap_or(&[]) {
+            moves.push(try!(self.board.parse_san(mv)));
+        }
+        Ok(moves)
+    }
+
+    /// The expected perft results given by the D1 to D6 operations, as pairs of depth and count
+    pub fn perft_counts(&self) -> Vec<(u8, usize)> {
+        self.ops.iter().filter_map(|&(ref op, ref operands)| {
+            if !op.starts_with('D') { return None }
+
+            match (op[1..].parse(), operands.first().and_then(|count| count.parse().ok())) {
+                (Ok(depth), Some(count)) => Some((depth, count)),
+                _ => None
+            }
+        }).collect()
+    }
+
+    /// Compare perft with the expected results up to a maximum depth, returning the first
+    /// mismatch as its depth, the expected count and the count found
+    pub fn check_perft(&self, max_depth: u8) -> Option<(u8, usize, usize)> {
+        self.perft_counts().into_iter()
+            .filter(|&(depth, _)| depth <= max_depth)
+            .map(|(depth, expected)| (depth, expected, self.board.perft(depth, false)))
+            .find(|&(_, expected, found)| found != expected)
+    }
+}
+
+/// Split operations at each semicolon, other than those inside a quoted string
+fn split_ops(text: &str) -> Vec<&str> {
+    let mut ops = Vec::new();
+    let (mut start, mut quoted) = (0, false);
+
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                ops.push(&text[start..i]);
+                start = i + 1;
+            },
+            _ => ()
+        }
+    }
+    ops.push(&text[start..]);
+    ops
+}
+
+/// Split an operation into its opcode and operands, where a quoted operand may contain spaces
+fn split_words(op: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+
+    for c in op.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            _ if c.is_whitespace() && !quoted => {
+                if !word.is_empty() { words.push(word.clone()) }
+                word.clear();
+            },
+            _ => word.push(c)
+        }
+    }
+    if !word.is_empty() || quoted { words.push(word) }
+    words
+}
+
+/// Search every position of a test suite, checking the best move against the bm and am
+/// operations. The search limits are those of `go`, and `count` gives the number of positions
+pub fn run(path: &str, params: &mut Params, searcher: &mut Searcher) -> Result<(), ParseError> {
+    let mut limits = Vec::new();
+    let mut count = usize::max_value();
+
+    while let Some(word) = params.next() {
+        match word {
+            "count" => count = try!(parse(params.next())),
+            _ => limits.push(word)
+        }
+    }
+
+    let limits = if limits.is_empty() { DEFAULT_LIMITS.into() } else { limits.join(" ") };
+    try!(searcher.timer.replace(&mut limits.split_whitespace()));
+
+    let (mut solved, mut total) = (0, 0);
+
+    uci::positions(path, count, searcher, &mut |searcher, epd| {
+        let id = epd.id().map_or(epd.board.to_fen(), String::from);
+
+        let (best_moves, avoid_moves) = match (epd.moves("bm"), epd.moves("am")) {
+            (Ok(bm), Ok(am)) => (bm, am),
+            (Err(e), _) | (_, Err(e)) => {
+                println!("{} skipped. {}", id, e);
+                return
+            }
+        };
+        if best_moves.is_empty() && avoid_moves.is_empty() {
+            println!("{} skipped, as it has no bm or am", id);
+            return
+        }
+
+        searcher.timer.replace(&mut limits.split_whitespace()).unwrap();
+        let mv = searcher.go();
+
+        let is_solved = (best_moves.is_empty() || best_moves.contains(&mv)) && !avoid_moves.contains(&mv);
+        let expected: Vec<String> = epd.operands("bm").map(|bm| format!("bm {}", bm.join(" ")))
+            .into_iter().chain(epd.operands("am").map(|am| format!("am {}", am.join(" "))))
+            .collect();
+
+        total += 1;
+        if is_solved { solved += 1 }
+
+        let san = if mv == Move::NULL { "no move".into() } else { epd.board.to_san(mv) };
+        println!("{} {} with {}, expected {}", id, if is_solved { "solved" } else { "failed" },
+                 san, expected.join(", "));
+    });
+
+    println!("Solved {} of {} positions", solved, total);
+    Ok(())
+}
+
+/// Check perft for every position of a suite up to a maximum depth. A position which fails
+/// is shown divided into the count after each of its moves
+pub fn perft_suite(path: &str, max_depth: u8, searcher: &mut Searcher) {
+    let (mut passed, mut total) = (0, 0);
+
+    uci::positions(path, usize::max_value(), searcher, &mut |_, epd| {
+        total += 1;
+
+        match epd.check_perft(max_depth) {
+            None => passed += 1,
+            Some((depth, expected, found)) => {
+                println!("Failed at depth {}, expected {} but found {}", depth, expected, found);
+                epd.board.perft(depth, true);
+            }
+        }
+    });
+
+    println!("Passed {} of {} positions", passed, total);
+}
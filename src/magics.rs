@@ -31,6 +31,19 @@ pub fn queen_moves(from: u32, occ: u64) -> u64 {
                ROOK_MAP[from as usize].att(occ) }
 }
 
+/// The squares strictly between two squares on the same row, file or diagonal, and otherwise none
+pub fn between(a: u32, b: u32) -> u64 {
+    let (a_bit, b_bit) = (1 << a, 1 << b);
+
+    if rook_moves(a, 0) & b_bit != 0 {
+        rook_moves(a, b_bit) & rook_moves(b, a_bit)
+    } else if bishop_moves(a, 0) & b_bit != 0 {
+        bishop_moves(a, b_bit) & bishop_moves(b, a_bit)
+    } else {
+        0
+    }
+}
+
 pub unsafe fn init() {
     king_map_init();
     knight_map_init();
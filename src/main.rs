@@ -1,19 +1,25 @@
-#![feature(slice_patterns, test, associated_consts, custom_attribute)]
+#![feature(slice_patterns, test, associated_consts, repr_align, custom_attribute)]
 extern crate itertools;
 extern crate rand;
 extern crate time;
 extern crate test;
 
-#[macro_use]
 pub mod util;
 
 pub mod bitboard;
 pub mod board;
+pub mod book;
+pub mod engine;
+pub mod epd;
 pub mod evaluation;
 pub mod magics;
+pub mod movepick;
+pub mod perft;
 pub mod _move;
+pub mod pgn;
 pub mod print;
 pub mod search;
+pub mod syzygy;
 pub mod table;
 pub mod testing;
 pub mod timer;
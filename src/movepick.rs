@@ -0,0 +1,295 @@
+//! Ordering the moves of a node, generating and scoring each type of move only when it is reached
+use std::cmp::min;
+use types::*;
+use board::{Board, MoveType};
+use _move::{Move, Killer};
+
+/// The limit of each history score, in either direction
+pub const HISTORY_MAX: i32 = 1 << 14;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Stage {
+    HashMove, GenCaptures, GoodCaptures, Killers, CounterMove, GenQuiets, Quiets, BadCaptures,
+    GenChecks, Checks, Done
+}
+
+/// Yields the hash move, then captures which don't lose material by MVV-LVA, the killers,
+/// the countermove, quiet moves by their history and finally the losing captures. Many nodes cut off on the
+/// hash move or a capture, before the quiet moves are even generated
+pub struct MovePicker<'a> {
+    board: &'a Board,
+    stage: Stage,
+    hash_move: Move,
+    killers: Killer,
+    counter: Move,
+    prev_moves: [PieceTo; 2],
+    moves: Vec<(i32, Move)>,
+    bad_captures: Vec<Move>,
+    index: usize,
+    quiescence: bool,
+    checks: bool
+}
+
+impl<'a> MovePicker<'a> {
+    /// A picker for a node reached by `prev_moves`, the last move first
+    pub fn new(board: &'a Board, hash_move: Move, killers: Killer, prev_moves: [PieceTo; 2]) -> Self {
+        MovePicker {
+            board: board,
+            stage: Stage::HashMove,
+            hash_move: hash_move,
+            killers: killers,
+            counter: Move::NULL,
+            prev_moves: prev_moves,
+            moves: Vec::new(),
+            bad_captures: Vec::new(),
+            index: 0,
+            quiescence: false,
+            checks: false
+        }
+    }
+
+    /// A picker for quiescence search, which only yields the captures that don't lose material,
+    /// followed by any quiet moves which give check if `checks` is set
+    pub fn quiescence(board: &'a Board, hash_move: Move, checks: bool) -> Self {
+        let hash_move = if hash_move.is_capture() || hash_move.promotion() != 0 { hash_move } else { Move::NULL };
+        let mut picker = MovePicker::new(board, hash_move, Killer::EMPTY, [PieceTo::NONE; 2]);
+        picker.quiescence = true;
+        picker.checks = checks;
+        picker
+    }
+
+    pub fn next(&mut self, history: &History) -> Option<Move> {
+        loop {
+            match self.stage {
+                Stage::HashMove => {
+                    self.stage = Stage::GenCaptures;
+                    if self.board.is_legal(self.hash_move) { return Some(self.hash_move) }
+                },
+                Stage::GenCaptures => {
+                    let (board, hash_move) = (self.board, self.hash_move);
+                    self.moves = board.legal_moves_of(MoveType::Captures).into_iter()
+                        .filter(|&mv| mv != hash_move)
+                        .map(|mv| (mvv_lva(board, mv), mv))
+                        .collect();
+                    self.index = 0;
+                    self.stage = Stage::GoodCaptures;
+                },
+                Stage::GoodCaptures => match self.pick_best() {
+                    Some(mv) => {
+                        if is_good_capture(self.board, mv) { return Some(mv) }
+                        if !self.quiescence { self.bad_captures.push(mv) }
+                    },
+                    None => {
+                        self.index = 0;
+                        self.stage = match (self.quiescence, self.checks) {
+                            (false, _)    => Stage::Killers,
+                            (true, true)  => Stage::GenChecks,
+                            (true, false) => Stage::Done
+                        };
+                    }
+                },
+                Stage::Killers => {
+                    if self.index == 2 {
+                        self.stage = Stage::CounterMove;
+                        continue
+                    }
+
+                    let killer = if self.index == 0 { self.killers.0 } else { self.killers.1 };
+                    self.index += 1;
+
+                    if    (self.index == 1 || killer != self.killers.0)
+                       && self.is_quiet_candidate(killer)
+                    {
+                        return Some(killer)
+                    }
+                },
+                Stage::CounterMove => {
+                    self.stage = Stage::GenQuiets;
+                    let counter = history.counter(&self.prev_moves);
+
+                    if    counter != self.killers.0
+                       && counter != self.killers.1
+                       && self.is_quiet_candidate(counter)
+                    {
+                        self.counter = counter;
+                        return Some(counter)
+                    }
+                },
+                Stage::GenQuiets => {
+                    let (board, prev_moves) = (self.board, self.prev_moves);
+                    let tried = [self.hash_move, self.killers.0, self.killers.1, self.counter];
+                    self.moves = board.legal_moves_of(MoveType::Quiets).into_iter()
+                        .filter(|mv| !tried.contains(mv))
+                        .map(|mv| (history.score(board, mv, &prev_moves), mv))
+                        .collect();
+                    self.index = 0;
+                    self.stage = Stage::Quiets;
+                },
+                Stage::Quiets => match self.pick_best() {
+                    Some(mv) => return Some(mv),
+                    None => {
+                        self.index = 0;
+                        self.stage = Stage::BadCaptures;
+                    }
+                },
+                Stage::BadCaptures => {
+                    if self.index < self.bad_captures.len() {
+                        self.index += 1;
+                        return Some(self.bad_captures[self.index - 1])
+                    }
+                    self.stage = Stage::Done;
+                },
+                Stage::GenChecks => {
+                    let (board, prev_moves) = (self.board, self.prev_moves);
+                    self.moves = board.legal_moves_of(MoveType::Quiets).into_iter()
+                        .filter(|&mv| {
+                            let mut new_board = *board;
+                            new_board.make_move(mv);
+                            new_board.is_in_check()
+                        })
+                        .map(|mv| (history.score(board, mv, &prev_moves), mv))
+                        .collect();
+                    self.index = 0;
+                    self.stage = Stage::Checks;
+                },
+                Stage::Checks => match self.pick_best() {
+                    Some(mv) => return Some(mv),
+                    None => self.stage = Stage::Done
+                },
+                Stage::Done => return None
+            }
+        }
+    }
+
+    /// Whether a killer or countermove, which was found in another position, can be tried here
+    fn is_quiet_candidate(&self, mv: Move) -> bool {
+        mv != self.hash_move && !mv.is_capture() && mv.promotion() == 0 && self.board.is_legal(mv)
+    }
+
+    /// Swap the best scored of the remaining moves to the front, rather than sorting them all
+    fn pick_best(&mut self) -> Option<Move> {
+        if self.index >= self.moves.len() { return None }
+
+        let mut best = self.index;
+        for i in self.index + 1..self.moves.len() {
+            if self.moves[i].0 > self.moves[best].0 { best = i }
+        }
+
+        self.moves.swap(self.index, best);
+        self.index += 1;
+        Some(self.moves[self.index - 1].1)
+    }
+}
+
+/// Most valuable victim, then least valuable attacker. A queen promotion counts as capturing
+/// the queen it gains
+fn mvv_lva(board: &Board, mv: Move) -> i32 {
+    let victim = if mv.is_en_passant() { PAWN } else { board.sqs[mv.to() as usize] };
+    let promoted = if mv.promotion() == QUEEN_PROM { p_val(QUEEN) } else { 0 };
+    let attacker = board.sqs[mv.from() as usize] & PIECE;
+
+    10 * (p_val(victim) + promoted) as i32 - attacker as i32
+}
+
+/// Whether a capture or promotion doesn't lose material. Taking a piece worth at least the
+/// attacker can't, so only the others need static exchange evaluation
+fn is_good_capture(board: &Board, mv: Move) -> bool {
+    if mv.promotion() != 0 && mv.promotion() != QUEEN_PROM { return false }
+    if mv.is_en_passant() { return true }
+
+    let victim = board.sqs[mv.to() as usize];
+    let attacker = board.sqs[mv.from() as usize];
+
+    mv.is_capture() && p_val(victim) >= p_val(attacker) || board.see_move(&mv) >= 0
+}
+
+/// The piece and destination of a move, by which the continuation history remembers it
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PieceTo {
+    pub piece: u8,
+    pub to: u32
+}
+
+impl PieceTo {
+    /// Before the root, or after a null move
+    pub const NONE: PieceTo = PieceTo { piece: EMPTY, to: 0 };
+
+    pub fn of(board: &Board, mv: Move) -> Self {
+        PieceTo { piece: board.sqs[mv.from() as usize], to: mv.to() }
+    }
+
+    fn index(&self) -> usize {
+        self.piece as usize * 64 + self.to as usize
+    }
+}
+
+/// What is known about quiet moves from the cutoffs so far. The butterfly table scores moves by
+/// side, origin and destination, the countermoves refute each previous move, and the continuation
+/// tables score a move by the piece and destination of the moves one and two plies before it
+pub struct History {
+    butterfly: Vec<i32>,
+    counters: Vec<Move>,
+    continuation: Vec<i32>
+}
+
+impl History {
+    pub fn new() -> Self {
+        History {
+            butterfly: vec![0; 2 * 64 * 64],
+            counters: vec![Move::NULL; 12 * 64],
+            continuation: vec![0; 12 * 64 * 12 * 64]
+        }
+    }
+
+    fn butterfly_index(color: u8, mv: Move) -> usize {
+        (color as usize) << 12 | (mv.from() as usize) << 6 | mv.to() as usize
+    }
+
+    fn continuation_index(prev: PieceTo, current: PieceTo) -> Option<usize> {
+        if prev.piece == EMPTY { None } else { Some(prev.index() * 12 * 64 + current.index()) }
+    }
+
+    /// The score of a quiet move, given the moves which led to the position from `prev_moves`
+    pub fn score(&self, board: &Board, mv: Move, prev_moves: &[PieceTo; 2]) -> i32 {
+        let current = PieceTo::of(board, mv);
+
+        prev_moves.iter().filter_map(|&prev| History::continuation_index(prev, current))
+            .fold(self.butterfly[History::butterfly_index(board.to_move, mv)], |score, i| score + self.continuation[i])
+    }
+
+    /// The move which last refuted the previous move
+    pub fn counter(&self, prev_moves: &[PieceTo; 2]) -> Move {
+        let prev = prev_moves[0];
+        if prev.piece == EMPTY { Move::NULL } else { self.counters[prev.index()] }
+    }
+
+    /// Reward the quiet move which caused a cutoff and penalise the quiet moves searched before it.
+    /// Each score moves towards the limit by the bonus in proportion to its distance from it,
+    /// so that no score can grow past it and recent cutoffs outweigh older ones
+    pub fn update(&mut self, board: &Board, best: Move, tried: &[Move], prev_moves: &[PieceTo; 2], depth: u8) {
+        let bonus = min(32 * depth as i32 * depth as i32, HISTORY_MAX / 4);
+
+        self.adjust(board, best, prev_moves, bonus);
+        for &mv in tried {
+            self.adjust(board, mv, prev_moves, -bonus);
+        }
+
+        let prev = prev_moves[0];
+        if prev.piece != EMPTY { self.counters[prev.index()] = best }
+    }
+
+    fn adjust(&mut self, board: &Board, mv: Move, prev_moves: &[PieceTo; 2], bonus: i32) {
+        let current = PieceTo::of(board, mv);
+        gravity(&mut self.butterfly[History::butterfly_index(board.to_move, mv)], bonus);
+
+        for &prev in prev_moves {
+            if let Some(i) = History::continuation_index(prev, current) {
+                gravity(&mut self.continuation[i], bonus);
+            }
+        }
+    }
+}
+
+fn gravity(score: &mut i32, bonus: i32) {
+    *score += bonus - *score * bonus.abs() / HISTORY_MAX;
+}
@@ -0,0 +1,172 @@
+//! Counting the leaves of the move tree to a fixed depth, for testing move generation
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use board::Board;
+use _move::Move;
+use table::Hash;
+use util::count;
+
+/// A table of subtree counts by position and depth, which threads share without locking.
+/// As in the transposition table, the key is stored xor'd with the data so that a torn
+/// write is never read back as a count
+pub struct PerftTable {
+    slots: Vec<(AtomicUsize, AtomicUsize)>
+}
+
+impl PerftTable {
+    pub fn new(size_mb: usize) -> Self {
+        let size = size_mb * 1024 * 1024 / mem::size_of::<(AtomicUsize, AtomicUsize)>();
+        let mut num_slots = 1;
+        while num_slots * 2 <= size {
+            num_slots *= 2;
+        }
+
+        PerftTable { slots: (0..num_slots).map(|_| (AtomicUsize::new(0), AtomicUsize::new(0))).collect() }
+    }
+
+    fn slot(&self, hash: Hash) -> &(AtomicUsize, AtomicUsize) {
+        &self.slots[hash.val as usize & (self.slots.len() - 1)]
+    }
+
+    fn probe(&self, hash: Hash, depth: u8) -> Option<usize> {
+        let &(ref key, ref data) = self.slot(hash);
+        let data = data.load(Ordering::Relaxed) as u64;
+        let key = key.load(Ordering::Relaxed) as u64;
+
+        if key ^ data == hash.val && data as u8 == depth { Some((data >> 8) as usize) } else { None }
+    }
+
+    /// Every entry is replaced, as the deepest counts are found last anyway
+    fn store(&self, hash: Hash, depth: u8, count: usize) {
+        let &(ref key, ref data) = self.slot(hash);
+        let packed = (count as u64) << 8 | depth as u64;
+
+        key.store((hash.val ^ packed) as usize, Ordering::Relaxed);
+        data.store(packed as usize, Ordering::Relaxed);
+    }
+}
+
+/// Count the legal move sequences of a given length. At the last ply the legal moves
+/// are only counted, rather than made
+pub fn perft(board: &Board, depth: u8, table: Option<&PerftTable>) -> usize {
+    if depth == 0 { return 1 }
+    if depth == 1 { return board.legal_moves().len() }
+
+    if let Some(count) = table.and_then(|table| table.probe(board.hash, depth)) {
+        return count
+    }
+
+    let mut total = 0;
+    for mv in board.legal_moves() {
+        let mut new_board = *board;
+        new_board.make_move(mv);
+        total += perft(&new_board, depth - 1, table);
+    }
+
+    if let Some(table) = table { table.store(board.hash, depth, total) }
+    total
+}
+
+/// Count below each legal move of a position, sharing the moves between several threads
+pub fn divide(board: &Board, depth: u8, threads: usize, table: Option<Arc<PerftTable>>) -> Vec<(Move, usize)> {
+    let moves = Arc::new(board.legal_moves());
+    let next = Arc::new(AtomicUsize::new(0));
+
+    let workers: Vec<_> = (0..threads).map(|_| {
+        let (board, moves, next, table) = (*board, moves.clone(), next.clone(), table.clone());
+
+        thread::spawn(move || {
+            let mut counts = Vec::new();
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= moves.len() { break }
+
+                let mut new_board = board;
+                new_board.make_move(moves[i]);
+                counts.push((i, perft(&new_board, depth - 1, table.as_ref().map(|table| &**table))));
+            }
+            counts
+        })
+    }).collect();
+
+    let mut counts = vec![0; moves.len()];
+    for worker in workers {
+        for (i, count) in worker.join().expect("A perft thread panicked") {
+            counts[i] = count;
+        }
+    }
+
+    moves.iter().cloned().zip(counts).collect()
+}
+
+/// Counts of each kind of move leading to the leaves, as in the standard perft tables
+#[derive(Copy, Clone, Default)]
+pub struct PerftStats {
+    pub nodes: usize,
+    pub captures: usize,
+    pub en_passants: usize,
+    pub castles: usize,
+    pub promotions: usize,
+    pub checks: usize,
+    pub discovered_checks: usize,
+    pub double_checks: usize,
+    pub checkmates: usize
+}
+
+impl PerftStats {
+    fn add(&mut self, other: &PerftStats) {
+        self.nodes += other.nodes;
+        self.captures += other.captures;
+        self.en_passants += other.en_passants;
+        self.castles += other.castles;
+        self.promotions += other.promotions;
+        self.checks += other.checks;
+        self.discovered_checks += other.discovered_checks;
+        self.double_checks += other.double_checks;
+        self.checkmates += other.checkmates;
+    }
+
+    /// Count a leaf, given the move which led to it
+    fn count(&mut self, leaf: &Board, mv: Move) {
+        self.nodes += 1;
+        if mv.is_capture()    { self.captures += 1 }
+        if mv.is_en_passant() { self.en_passants += 1 }
+        if mv.king_castle() || mv.queen_castle() { self.castles += 1 }
+        if mv.promotion() != 0 { self.promotions += 1 }
+
+        let checkers = leaf.checkers();
+        if checkers == 0 { return }
+        self.checks += 1;
+
+        // As in the reference tables, a check is only discovered if the piece which moved,
+        // which for castling is the rook, gives no check itself
+        let moved = if mv.king_castle() { mv.to() - 1 } else if mv.queen_castle() { mv.to() + 1 } else { mv.to() };
+        if checkers & 1 << moved == 0 { self.discovered_checks += 1 }
+        if count(checkers) > 1 { self.double_checks += 1 }
+        if !leaf.has_legal_move() { self.checkmates += 1 }
+    }
+}
+
+/// Perft which also counts each kind of move at the last ply
+pub fn stats(board: &Board, depth: u8) -> PerftStats {
+    let mut total = PerftStats::default();
+    if depth == 0 {
+        total.nodes = 1;
+        return total
+    }
+
+    for mv in board.legal_moves() {
+        let mut new_board = *board;
+        new_board.make_move(mv);
+
+        if depth == 1 {
+            total.count(&new_board, mv);
+        } else {
+            total.add(&stats(&new_board, depth - 1));
+        }
+    }
+    total
+}
@@ -0,0 +1,343 @@
+//! Reading and writing games in Portable Game Notation
+use std::cmp::min;
+use std::iter::Peekable;
+
+use util::*;
+use board::Board;
+use _move::Move;
+use print::mate_in;
+
+/// The traditional suffix annotations, and the NAG each of them stands for
+const SUFFIXES: [(&'static str, u8); 6] = [("!", 1), ("?", 2), ("!!", 3), ("??", 4), ("!?", 5), ("?!", 6)];
+
+/// A move of a game, with the annotations around it
+#[derive(Clone)]
+pub struct Node {
+    pub mv: Move,
+    pub nags: Vec<u8>,
+    /// Comments before the move, which only occur at the start of a game or variation
+    pub leading: Vec<String>,
+    pub comments: Vec<String>,
+    /// Alternatives to this move, each played from the position before it
+    pub variations: Vec<Vec<Node>>
+}
+
+impl Node {
+    pub fn new(mv: Move) -> Self {
+        Node { mv: mv, nags: Vec::new(), leading: Vec::new(), comments: Vec::new(), variations: Vec::new() }
+    }
+}
+
+#[derive(Clone)]
+pub struct Game {
+    pub tags: Vec<(String, String)>,
+    pub start: Board,
+    pub moves: Vec<Node>,
+    pub result: String
+}
+
+impl Game {
+    pub fn new(start: Board) -> Self {
+        Game { tags: Vec::new(), start: start, moves: Vec::new(), result: "*".into() }
+    }
+
+    /// Read the first game of some PGN text
+    pub fn parse(text: &str) -> Result<Self, ParseError> {
+        games(text).next().unwrap_or(Err(ParseError::Missing("game")))
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.iter().find(|&&(ref tag, _)| tag == name).map(|&(_, ref value)| &value[..])
+    }
+
+    pub fn set_tag(&mut self, name: &str, value: &str) {
+        match self.tags.iter().position(|&(ref tag, _)| tag == name) {
+            Some(i) => self.tags[i].1 = value.into(),
+            None => self.tags.push((name.into(), value.into()))
+        }
+    }
+
+    /// Add a move to the end of the main line
+    pub fn push(&mut self, mv: Move) {
+        self.moves.push(Node::new(mv));
+    }
+
+    /// Add a move with the engine's evaluation of it, from the side making the move
+    pub fn push_eval(&mut self, mv: Move, score: i32, depth: usize) {
+        let mut node = Node::new(mv);
+        node.comments.push(eval_str(score, depth));
+        self.moves.push(node);
+    }
+
+    /// Each move of the main line, with the position it is played from
+    pub fn positions(&self) -> Vec<(Board, Move)> {
+        let mut board = self.start;
+        self.moves.iter().map(|node| {
+            let position = board;
+            board.make_move(node.mv);
+            (position, node.mv)
+        }).collect()
+    }
+
+    /// The position at the end of the main line
+    pub fn board(&self) -> Board {
+        let mut board = self.start;
+        for node in &self.moves {
+            board.make_move(node.mv);
+        }
+        board
+    }
+}
+
+/// Format an evaluation in the usual way for PGN comments, such as +0.35/12 or -M3/20
+pub fn eval_str(score: i32, depth: usize) -> String {
+    match mate_in(score) {
+        Some(moves) if moves > 0 => format!("+M{}/{}", moves, depth),
+        Some(moves) => format!("-M{}/{}", -moves, depth),
+        None => format!("{:+.2}/{}", score as f64 / 1000.0, depth)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+enum Token<'a> {
+    Tag(&'a str, String),
+    Comment(&'a str),
+    Nag(u8),
+    Open,
+    Close,
+    Result(&'a str),
+    Move(&'a str),
+    Unknown(&'a str)
+}
+
+struct Tokens<'a> {
+    text: &'a str,
+    pos: usize
+}
+
+impl<'a> Tokens<'a> {
+    /// Skip the rest of the current line, returning it
+    fn line(&mut self) -> &'a str {
+        let rest = &self.text[self.pos..];
+        let end = rest.find('\n').unwrap_or(rest.len());
+        self.pos += end;
+        &rest[..end]
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        loop {
+            let rest = &self.text[self.pos..];
+            let c = match rest.chars().next() {
+                Some(c) => c,
+                None => return None
+            };
+
+            match c {
+                _ if c.is_whitespace() => self.pos += c.len_utf8(),
+                // A line starting with % is an escape for other programs
+                '%' if self.text[..self.pos].ends_with('\n') || self.pos == 0 => { self.line(); },
+                ';' => return Some(Token::Comment(self.line()[1..].trim())),
+                '{' => {
+                    let end = rest.find('}').unwrap_or(rest.len());
+                    self.pos += min(end + 1, rest.len());
+                    return Some(Token::Comment(rest[1..end].trim()))
+                },
+                '(' => { self.pos += 1; return Some(Token::Open) },
+                ')' => { self.pos += 1; return Some(Token::Close) },
+                '[' => {
+                    return Some(match parse_tag(rest) {
+                        Some((name, value, len)) => {
+                            self.pos += len;
+                            Token::Tag(name, value)
+                        },
+                        None => Token::Unknown(self.line())
+                    })
+                },
+                '$' => {
+                    let len = rest[1..].find(|c: char| !c.is_digit(10)).unwrap_or(rest.len() - 1) + 1;
+                    self.pos += len;
+                    return Some(rest[1..len].parse().map(Token::Nag).unwrap_or(Token::Unknown(&rest[..len])))
+                },
+                _ => {
+                    let len = rest.find(|c: char| c.is_whitespace() || "{}()[];$\"".contains(c))
+                                  .unwrap_or(rest.len());
+                    if len == 0 {
+                        self.pos += c.len_utf8();
+                        return Some(Token::Unknown(&rest[..c.len_utf8()]))
+                    }
+                    self.pos += len;
+
+                    let symbol = &rest[..len];
+                    match symbol {
+                        "1-0" | "0-1" | "1/2-1/2" | "*" => return Some(Token::Result(symbol)),
+                        _ => ()
+                    }
+
+                    // Move numbers are skipped, as in 12. or 12... or even 12...Nf6
+                    if symbol.starts_with(|c: char| c.is_digit(10)) && !symbol.starts_with("0-0") {
+                        let san = symbol.trim_left_matches(|c: char| c.is_digit(10)).trim_left_matches('.');
+                        if !san.is_empty() { return Some(Token::Move(san)) }
+                    } else {
+                        return Some(Token::Move(symbol))
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Read a tag pair such as [Event "F/S Return Match"], returning its name, value and length
+fn parse_tag(text: &str) -> Option<(&str, String, usize)> {
+    let inner = text[1..].trim_left();
+    let name_len = inner.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(inner.len());
+    if name_len == 0 { return None }
+
+    let name = &inner[..name_len];
+    let after_name = inner[name_len..].trim_left();
+    if !after_name.starts_with('"') { return None }
+
+    let mut value = String::new();
+    let mut chars = after_name.char_indices().skip(1);
+    let mut end = None;
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => if let Some((_, escaped)) = chars.next() { value.push(escaped) },
+            '"'  => { end = Some(i + 1); break },
+            '\n' => return None,
+            _    => value.push(c)
+        }
+    }
+
+    let after_value = match end {
+        Some(end) => &after_name[end..],
+        None => return None
+    };
+    let close = after_value.trim_left();
+    if !close.starts_with(']') { return None }
+
+    Some((name, value, text.len() - close.len() + 1))
+}
+
+/// The games of some PGN text, in order. A game which can't be read is skipped,
+/// after giving its error
+pub struct Games<'a> {
+    tokens: Peekable<Tokens<'a>>
+}
+
+pub fn games<'a>(text: &'a str) -> Games<'a> {
+    Games { tokens: Tokens { text: text, pos: 0 }.peekable() }
+}
+
+impl<'a> Iterator for Games<'a> {
+    type Item = Result<Game, ParseError>;
+
+    fn next(&mut self) -> Option<Result<Game, ParseError>> {
+        if self.tokens.peek().is_none() { return None }
+
+        let game = self.parse_game();
+        if game.is_err() { self.skip_game() }
+        Some(game)
+    }
+}
+
+impl<'a> Games<'a> {
+    fn parse_game(&mut self) -> Result<Game, ParseError> {
+        let mut game = Game::new(Board::start_position());
+
+        while let Some(&Token::Tag(..)) = self.tokens.peek() {
+            if let Some(Token::Tag(name, value)) = self.tokens.next() {
+                game.tags.push((name.into(), value));
+            }
+        }
+
+        if let Some(fen) = game.tag("FEN").map(String::from) {
+            game.start = try!(Board::from_fen(&mut fen.split_whitespace()));
+        }
+
+        let mut leading = Vec::new();
+        game.moves = try!(self.parse_line(game.start, &mut leading, false));
+
+        game.result = match self.tokens.peek() {
+            Some(&Token::Result(result)) => result.into(),
+            _ => game.tag("Result").unwrap_or("*").into()
+        };
+        if let Some(&Token::Result(_)) = self.tokens.peek() { self.tokens.next(); }
+
+        Ok(game)
+    }
+
+    /// Read a sequence of moves with their annotations, up to the end of the game or variation
+    fn parse_line(&mut self, mut board: Board, leading: &mut Vec<String>, nested: bool)
+            -> Result<Vec<Node>, ParseError> {
+        let mut line: Vec<Node> = Vec::new();
+        let mut prev = board;
+
+        loop {
+            match self.tokens.peek() {
+                None if nested => return Err(ParseError::Missing("closing parenthesis")),
+                Some(&Token::Close) if nested => { self.tokens.next(); break },
+                None | Some(&Token::Tag(..)) | Some(&Token::Result(_)) if !nested => break,
+                _ => ()
+            }
+
+            match self.tokens.next().unwrap() {
+                Token::Comment(comment) => match line.last_mut() {
+                    Some(node) => node.comments.push(comment.into()),
+                    None => leading.push(comment.into())
+                },
+                Token::Nag(nag) => match line.last_mut() {
+                    Some(node) => node.nags.push(nag),
+                    None => return Err(ParseError::InvalidPgn(format!("${}", nag)))
+                },
+                Token::Open => {
+                    // Comments before the first move of the variation become its leading comments
+                    let variation = try!(self.parse_line(prev, &mut Vec::new(), true));
+
+                    match (line.last_mut(), variation.is_empty()) {
+                        (Some(node), false) => node.variations.push(variation),
+                        (Some(_), true) => (),
+                        (None, _) => return Err(ParseError::InvalidPgn("(".into()))
+                    }
+                },
+                Token::Move(symbol) => {
+                    // Move suffixes such as !? are the same as a NAG
+                    let san = symbol.trim_right_matches(|c| c == '!' || c == '?');
+                    let suffix = &symbol[san.len()..];
+                    let mv = try!(board.parse_san(san));
+
+                    let mut node = Node::new(mv);
+                    node.nags.extend(SUFFIXES.iter().find(|&&(s, _)| s == suffix).map(|&(_, nag)| nag));
+                    if line.is_empty() { node.leading = leading.drain(..).collect() }
+                    line.push(node);
+
+                    prev = board;
+                    board.make_move(mv);
+                },
+                Token::Tag(name, _) => return Err(ParseError::InvalidPgn(format!("[{}", name))),
+                Token::Close  => return Err(ParseError::InvalidPgn(")".into())),
+                Token::Result(result) | Token::Unknown(result) => return Err(ParseError::InvalidPgn(result.into()))
+            }
+        }
+        Ok(line)
+    }
+
+    /// Skip past the rest of a game, up to its result or the tags of the next game
+    fn skip_game(&mut self) {
+        let mut in_moves = false;
+
+        while let Some(token) = self.tokens.peek().cloned() {
+            match token {
+                Token::Tag(..) if in_moves => break,
+                Token::Tag(..) => (),
+                Token::Result(_) => { self.tokens.next(); break },
+                _ => in_moves = true
+            }
+            self.tokens.next();
+        }
+    }
+}
@@ -2,11 +2,20 @@
 use std::ascii::AsciiExt;
 use std::fmt::{Display, Formatter, Result};
 
-use board::Board;
+use board::{Board, GameState, START_FEN};
 use _move::Move;
+use perft::PerftStats;
+use pgn::{Game, Node};
+use search::{VALUE_MATE, MATE_BOUND};
 use types::*;
 use util::*;
 
+/// The tags which every exported game starts with, in this order
+const ROSTER: [&'static str; 7] = ["Event", "Site", "Date", "Round", "White", "Black", "Result"];
+
+/// Movetext is wrapped to lines of at most this many characters
+const LINE_WIDTH: usize = 80;
+
 impl Display for Board {
     fn fmt(&self, f: &mut Formatter) -> Result {
         let mut characters = Vec::with_capacity(64*2 + 8*2);
@@ -32,8 +41,55 @@ impl Display for Board {
                   -----------------\n\
                   Move # {}\n\
                   en passant {}{}\n\
-                  castling {:04b}\n",
-                  board, self.ply + 1, ep_str.0, ep_str.1, self.castling)
+                  castling {:04b}\n\
+                  fen {}\n",
+                  board, self.fullmove(), ep_str.0, ep_str.1, self.castling, self.to_fen())
+    }
+}
+
+impl Display for GameState {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let description = match *self {
+            GameState::Ongoing    => "ongoing",
+            GameState::Checkmate  => "checkmate",
+            GameState::Stalemate  => "stalemate",
+            GameState::FiftyMoves => "draw by the fifty move rule",
+            GameState::Repetition => "draw by threefold repetition"
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl Display for PerftStats {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "nodes {}\n\
+                   captures {}\n\
+                   en passant {}\n\
+                   castles {}\n\
+                   promotions {}\n\
+                   checks {}\n\
+                   discovered checks {}\n\
+                   double checks {}\n\
+                   checkmates {}\n",
+                   self.nodes, self.captures, self.en_passants, self.castles, self.promotions,
+                   self.checks, self.discovered_checks, self.double_checks, self.checkmates)
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match *self {
+            ParseError::Missing(field)          => write!(f, "Missing {}", field),
+            ParseError::InvalidValue(ref word)  => write!(f, "Invalid value {}", word),
+            ParseError::InvalidBoard(ref word)  => write!(f, "Invalid board {}", word),
+            ParseError::InvalidSide(ref word)   => write!(f, "Invalid side to move {}", word),
+            ParseError::InvalidCastling(ref word)  => write!(f, "Invalid castling rights {}", word),
+            ParseError::InvalidEnPassant(ref word) => write!(f, "Invalid en passant square {}", word),
+            ParseError::InvalidMove(ref word)   => write!(f, "Invalid move {}", word),
+            ParseError::IllegalMove(ref word)   => write!(f, "Illegal move {}", word),
+            ParseError::AmbiguousMove(ref word) => write!(f, "Ambiguous move {}", word),
+            ParseError::InvalidPgn(ref word)    => write!(f, "Invalid PGN {}", word)
+        }
     }
 }
 
@@ -87,6 +143,16 @@ pub fn to_char(sq: u8) -> char {
     if sq & COLOR == WHITE { ch.to_ascii_uppercase() } else { ch }
 }
 
+/// The upper case letter of the piece a move promotes to
+pub fn prom_char(promotion: u32) -> char {
+    match promotion {
+        KNIGHT_PROM => 'N',
+        BISHOP_PROM => 'B',
+        ROOK_PROM   => 'R',
+        _           => 'Q'
+    }
+}
+
 pub fn to_pos(col: char, row: char) -> u32 {
     let col_num = col as u8 - b'a';
     let row_num = row as u8 - b'1';
@@ -97,3 +163,117 @@ pub fn from_pos(pos: u32) -> (char, char) {
     let (row, col) = (pos / 8, pos % 8);
     ((col as u8 + b'a') as char, (row as u8 + b'1') as char)
 }
+
+/// Format a score for uci output, either in centipawns or as the number of moves to mate
+pub fn score_str(score: i32) -> String {
+    match mate_in(score) {
+        Some(moves) => format!("mate {}", moves),
+        None => format!("cp {}", score / 10)
+    }
+}
+
+/// The number of moves to mate of a mate score, which is negative when getting mated
+pub fn mate_in(score: i32) -> Option<i32> {
+    if score >= MATE_BOUND {
+        Some((VALUE_MATE - score + 1) / 2)
+    } else if score <= -MATE_BOUND {
+        Some(-(VALUE_MATE + score) / 2)
+    } else {
+        None
+    }
+}
+
+impl Display for Game {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        for &name in &ROSTER {
+            let value = match name {
+                "Result" => &self.result[..],
+                "Date" => self.tag(name).unwrap_or("????.??.??"),
+                _ => self.tag(name).unwrap_or("?")
+            };
+            try!(write_tag(f, name, value));
+        }
+
+        let fen = self.start.to_fen();
+        if fen != START_FEN {
+            try!(write_tag(f, "SetUp", "1"));
+            try!(write_tag(f, "FEN", &fen));
+        }
+
+        for &(ref name, ref value) in &self.tags {
+            if !ROSTER.contains(&&name[..]) && name != "SetUp" && name != "FEN" {
+                try!(write_tag(f, name, value));
+            }
+        }
+
+        let mut words = Vec::new();
+        write_line(self.start, &self.moves, &mut words);
+        words.push(self.result.clone());
+
+        try!(writeln!(f, ""));
+        let mut width = 0;
+        for word in words {
+            if width > 0 && width + 1 + word.len() > LINE_WIDTH {
+                try!(writeln!(f, ""));
+                width = 0;
+            }
+            if width > 0 {
+                try!(write!(f, " "));
+                width += 1;
+            }
+            try!(write!(f, "{}", word));
+            width += word.len();
+        }
+        writeln!(f, "\n")
+    }
+}
+
+fn write_tag(f: &mut Formatter, name: &str, value: &str) -> Result {
+    writeln!(f, "[{} \"{}\"]", name, value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Add a comment as separate words, so that it can be wrapped. A brace would end the comment
+/// early, so any in it are dropped
+fn write_comment(comment: &str, words: &mut Vec<String>) {
+    let text = format!("{{{}}}", comment.replace('}', ""));
+    words.extend(text.split_whitespace().map(String::from));
+}
+
+/// Add the movetext of a line of moves, starting from a given position
+fn write_line(mut board: Board, line: &[Node], words: &mut Vec<String>) {
+    // The move number is repeated before a move by black if anything came between
+    let mut needs_number = true;
+
+    for node in line {
+        for comment in &node.leading {
+            write_comment(comment, words);
+        }
+
+        // The move number is kept with its move, so that they aren't split across lines
+        let san = board.to_san(node.mv);
+        words.push(if board.to_move == WHITE {
+            format!("{}. {}", board.fullmove(), san)
+        } else if needs_number || !node.leading.is_empty() {
+            format!("{}... {}", board.fullmove(), san)
+        } else {
+            san
+        });
+        words.extend(node.nags.iter().map(|nag| format!("${}", nag)));
+
+        for comment in &node.comments {
+            write_comment(comment, words);
+        }
+
+        for variation in &node.variations {
+            let start = words.len();
+            write_line(board, variation, words);
+            if words.len() > start {
+                words[start].insert(0, '(');
+                words.last_mut().unwrap().push(')');
+            }
+        }
+
+        needs_number = !node.comments.is_empty() || !node.variations.is_empty();
+        board.make_move(node.mv);
+    }
+}
@@ -1,31 +1,104 @@
 use std::i32;
 use std::cmp::{min, max};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
 use timer::Timer;
 use types::*;
 use _move::*;
-use board::Board;
+use board::{Board, GameState};
+use book::Book;
+use movepick::{MovePicker, History, PieceTo, HISTORY_MAX};
+use syzygy::*;
 use table::*;
 use uci::EngineSettings;
-use util::parse;
+use print::score_str;
+use util::{parse, ParseError};
 
 pub const INFINITY: i32 = i32::MAX;
 pub const VALUE_MATE: i32 = INFINITY / 2;
+pub const MAX_DEPTH: usize = 100;
 
-#[derive(PartialEq, Eq)]
+/// Any score at least this large in magnitude is a forced mate
+pub const MATE_BOUND: i32 = VALUE_MATE - 2 * MAX_DEPTH as i32;
+
+/// The score of a position known to be won from the tablebases, above any evaluation
+pub const TB_WIN: i32 = 1_000_000;
+
+/// The distance from the previous score to each side of the first aspiration window
+const ASPIRATION_DELTA: i32 = 200;
+
+/// Each kind of forward pruning is only tried within a number of plies of the horizon. The margins
+/// are how far, per ply of depth, the static evaluation must be outside the window
+const REVERSE_FUTILITY_DEPTH: u8 = 6;
+const REVERSE_FUTILITY_MARGIN: i32 = 800;
+const RAZOR_DEPTH: u8 = 2;
+const RAZOR_MARGIN: i32 = 2500;
+const FUTILITY_DEPTH: u8 = 3;
+const FUTILITY_MARGIN: i32 = 1200;
+
+/// Late move pruning skips the quiet moves after this many, plus the square of the depth
+const LATE_MOVE_DEPTH: u8 = 4;
+const LATE_MOVE_COUNT: usize = 3;
+
+/// The hash move is singular if every other move fails below the table's score by this margin
+/// per ply of depth, which is only tested for deeper nodes
+const SINGULAR_DEPTH: u8 = 8;
+const SINGULAR_MARGIN: i32 = 20;
+
+/// How much a capture in quiescence search must be able to gain beyond the captured piece
+const DELTA_MARGIN: i32 = 2000;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub enum NT {
-    Root, PV, NonPV
+    PV, NonPV
+}
+
+/// A legal move at the root, with the score and principal variation of its latest search
+#[derive(Clone)]
+pub struct RootMove {
+    pub mv: Move,
+    pub score: i32,
+    pub pv: Vec<Move>
+}
+
+impl RootMove {
+    pub fn new(mv: Move) -> Self {
+        RootMove { mv: mv, score: -INFINITY, pv: vec![mv] }
+    }
 }
 
 pub struct Searcher {
     pub root: Board,
+    /// Zero for the main thread, which alone prints its progress
+    thread_id: usize,
     pub timer: Timer,
     settings: EngineSettings,
-    table: Table,
+    table: Arc<Table>,
+    book: Option<Book>,
+    tablebases: Arc<Tablebases>,
     killers: Vec<Killer>,
+    history: History,
+    moved: Vec<PieceTo>,
+    /// The move made at each ply, with the null move for a null move search
+    line: Vec<Move>,
+    /// The move left out by the search at each ply, when testing whether the hash move is singular
+    excluded: Vec<Move>,
     rep: Vec<Hash>,
+    root_rep: usize,
     ply: usize,
     node_count: usize,
-    irreversible: usize
+    /// The nodes searched by every thread, for the output and the node limit
+    nodes: Arc<AtomicUsize>,
+    tb_hits: usize,
+    /// The result of the root from the tablebases, which the search is unlikely to see
+    tb_score: Option<i32>,
+    root_moves: Vec<RootMove>,
+    pv_idx: usize,
+    root_depth: usize,
+    pv_table: Vec<Vec<Move>>,
+    completed_depth: usize,
+    stopped: bool
 }
 
 impl Searcher {
@@ -35,192 +108,670 @@ impl Searcher {
 
         Searcher {
             root: start,
+            thread_id: 0,
             timer: timer,
             settings: settings,
-            table: Table::empty(settings.table_size),
-            killers: vec![Killer::EMPTY],
+            table: Arc::new(Table::empty(settings.table_size)),
+            book: None,
+            tablebases: Arc::new(Tablebases::empty()),
+            killers: vec![Killer::EMPTY; MAX_DEPTH + 1],
+            history: History::new(),
+            moved: vec![PieceTo::NONE; MAX_DEPTH + 1],
+            line: vec![Move::NULL; MAX_DEPTH + 1],
+            excluded: vec![Move::NULL; MAX_DEPTH + 1],
             rep: vec![start.hash],
+            root_rep: 0,
             ply: 0,
             node_count: 0,
-            irreversible: 0
+            nodes: Arc::new(AtomicUsize::new(0)),
+            tb_hits: 0,
+            tb_score: None,
+            root_moves: Vec::new(),
+            pv_idx: 0,
+            root_depth: 0,
+            pv_table: vec![Vec::new(); MAX_DEPTH + 1],
+            completed_depth: 0,
+            stopped: false
         }
     }
 
-    pub fn update_settings(&mut self, params: &mut Params) {
+    /// Create a helper for lazy SMP, which searches the same root and shares the same table
+    pub fn helper(&self, id: usize, should_stop: Flag) -> Self {
+        Searcher {
+            root: self.root,
+            thread_id: id,
+            timer: Timer::infinite(should_stop),
+            settings: self.settings,
+            table: self.table.clone(),
+            book: None,
+            tablebases: self.tablebases.clone(),
+            killers: vec![Killer::EMPTY; MAX_DEPTH + 1],
+            history: History::new(),
+            moved: vec![PieceTo::NONE; MAX_DEPTH + 1],
+            line: vec![Move::NULL; MAX_DEPTH + 1],
+            excluded: vec![Move::NULL; MAX_DEPTH + 1],
+            rep: self.rep.clone(),
+            root_rep: self.root_rep,
+            ply: 0,
+            node_count: 0,
+            nodes: self.nodes.clone(),
+            tb_hits: 0,
+            tb_score: None,
+            root_moves: self.root_moves.clone(),
+            pv_idx: 0,
+            root_depth: 0,
+            pv_table: vec![Vec::new(); MAX_DEPTH + 1],
+            completed_depth: 0,
+            stopped: false
+        }
+    }
+
+    pub fn update_settings(&mut self, params: &mut Params) -> Result<(), ParseError> {
         while let Some(_) = params.find(|&word| word == "name") {
-            let setting: &str = &params.next().unwrap().to_lowercase();
+            let setting: &str = &try!(params.next().ok_or(ParseError::Missing("option name"))).to_lowercase();
 
             match setting {
                 "hash" => {
-                    let size_mb = parse(params.nth(1));
-                    self.table = Table::empty_mb(size_mb);
+                    let size_mb = try!(parse(params.nth(1)));
+                    self.table = Arc::new(Table::empty_mb(size_mb));
                     self.settings.table_size = self.table.size();
                 },
+                "multipv" => self.settings.multi_pv = max(1, try!(parse(params.nth(1)))),
+                "threads" => self.settings.threads = max(1, try!(parse(params.nth(1)))),
+                "ownbook" => self.settings.own_book = try!(parse(params.nth(1))),
+                "bookbestmove" => self.settings.book_best = try!(parse(params.nth(1))),
+                "recaptureextension" => self.settings.recapture_extension = try!(parse(params.nth(1))),
+                "passedpawnextension" => self.settings.passed_pawn_extension = try!(parse(params.nth(1))),
+                "quiescencechecks" => self.settings.quiescence_checks = try!(parse(params.nth(1))),
+                "bookfile" => {
+                    // The path may contain spaces, so it is the rest of the line
+                    let path: Vec<&str> = params.by_ref().skip(1).collect();
+                    self.open_book(&path.join(" "));
+                },
+                "syzygypath" => {
+                    let path: Vec<&str> = params.by_ref().skip(1).collect();
+                    self.open_tablebases(&path.join(" "));
+                },
                 _ => ()
             }
         }
+        Ok(())
     }
 
     pub fn reset(&mut self) {
-        self.table.entries = vec![]; // Explicitly drop the previous table
+        self.table = Arc::new(Table::empty(0)); // Explicitly drop the previous table
         let def_timer = Timer::default(self.timer.should_stop.clone());
+        let book = self.book.take();
+        let tablebases = self.tablebases.clone();
         *self = Searcher::new(self.settings, def_timer);
+        self.book = book;
+        self.tablebases = tablebases;
     }
 
-    pub fn extend(&mut self) {
-        self.killers.push(Killer::EMPTY);
-        self.rep.push(Hash { val: 0 });
+    pub fn open_book(&mut self, path: &str) {
+        self.book = None;
+        if path.is_empty() || path == "<empty>" { return }
+
+        match Book::open(path) {
+            Ok(book) => self.book = Some(book),
+            Err(e) => println!("info string Could not open book {}. {}", path, e)
+        }
     }
 
-    pub fn position(&mut self, params: &mut Params) {
-        self.root = match params.next().expect("[startpos, fen]") {
-            "startpos" => Board::start_position(),
-            _fen       => Board::from_fen(params)
-        };
+    pub fn open_tablebases(&mut self, path: &str) {
+        if path.is_empty() || path == "<empty>" {
+            self.tablebases = Arc::new(Tablebases::empty());
+            return
+        }
 
-        self.node_count = 0;
-        self.rep = vec![self.root.hash];
-        self.killers = vec![];
+        let tablebases = Tablebases::new(path);
+        println!("info string Found {} tablebases with up to {} pieces", tablebases.len(), tablebases.max_pieces);
+        self.tablebases = Arc::new(tablebases);
+    }
+
+    /// Choose a move from the opening book, if it is enabled and has any for the root.
+    /// The book is only used when playing on a clock, not when asked for analysis
+    pub fn book_move(&mut self) -> Option<Move> {
+        if !self.settings.own_book || !self.timer.is_timed() { return None }
+
+        let legal: Vec<Move> = self.root_moves.iter().map(|root_move| root_move.mv).collect();
+        let (root, best) = (self.root, self.settings.book_best);
+
+        self.book.as_mut().and_then(|book| book.choose(&root, &legal, best))
+    }
+
+    /// Set up the root position, which is left unchanged if any part of the command is invalid
+    pub fn position(&mut self, params: &mut Params) -> Result<(), ParseError> {
+        let mut root = match params.next() {
+            Some("startpos") => Board::start_position(),
+            Some("fen")      => try!(Board::from_fen(params)),
+            Some(word)       => return Err(ParseError::InvalidValue(word.into())),
+            None             => return Err(ParseError::Missing("startpos or fen"))
+        };
+        let mut rep = vec![root.hash];
 
         // Remove half move, full move, and other words until there are moves
         for mv_str in params.skip_while(|&val| val != "moves").skip(1) {
-            let mv = self.root.move_from_str(mv_str);
-            if self.root.is_irreversible(mv) {
-                self.irreversible = self.root.ply + 1;
-            }
-            self.root.make_move(mv);
-            self.rep.push(self.root.hash);
+            let mv = try!(root.move_from_str(mv_str));
+            root.make_move(mv);
+            rep.push(root.hash);
+        }
+
+        self.set_position(root, rep);
+        Ok(())
+    }
+
+    /// Set the root, given the hashes of every position up to and including it
+    pub fn set_position(&mut self, root: Board, rep: Vec<Hash>) {
+        self.root = root;
+        self.node_count = 0;
+        self.rep = rep;
+        self.root_rep = self.rep.len() - 1;
+        self.killers = vec![Killer::EMPTY; MAX_DEPTH + 1];
+    }
+
+    /// Whether the game is already over at the root, given the moves which led to it
+    pub fn game_state(&self) -> GameState {
+        self.root.game_state(&self.rep[..self.root_rep + 1])
+    }
+
+    /// Collect the legal moves at the root, restricted to any given by `go searchmoves`
+    pub fn init_root_moves(&mut self) {
+        let root = self.root;
+        let search_moves: Vec<Move> = self.timer.search_moves().iter().filter_map(|mv|
+            match root.move_from_str(mv) {
+                Ok(mv) => Some(mv),
+                Err(e) => {
+                    println!("info string Ignoring search move. {}", e);
+                    None
+                }
+            }).collect();
+
+        self.root_moves = root.legal_moves().into_iter()
+            .filter(|mv| search_moves.is_empty() || search_moves.contains(mv))
+            .map(RootMove::new)
+            .collect();
+    }
+
+    /// If the root is in the tablebases, keep only the moves which preserve the best result,
+    /// preferring the fastest progress towards a win. The search then chooses between them
+    pub fn filter_root_moves(&mut self) {
+        self.tb_score = None;
+        if self.root_moves.is_empty() || !self.tablebases.can_probe(&self.root) { return }
+
+        let moves: Vec<Move> = self.root_moves.iter().map(|root_move| root_move.mv).collect();
+
+        if let Some(ranks) = self.tablebases.rank_root_moves(&self.root, &moves) {
+            self.tb_hits += moves.len();
+            let best = *ranks.iter().max().unwrap();
+
+            // Results which the fifty move rule turns into draws are scored as draws
+            self.tb_score = Some(if best >= 1000 { TB_WIN } else if best <= -1000 { -TB_WIN } else { 0 });
+
+            self.root_moves = self.root_moves.drain(..).zip(ranks)
+                .filter(|&(_, rank)| rank == best)
+                .map(|(root_move, _)| root_move)
+                .collect();
         }
     }
 
-    pub fn go(&mut self) {
+    /// Search the root, returning the best move after printing it
+    pub fn go(&mut self) -> Move {
+        let best = self.think();
+        println!("bestmove {}", best);
+        best
+    }
+
+    /// Search the root and return the best move, leaving the caller to print it
+    pub fn think(&mut self) -> Move {
         assert!(self.ply == 0, "Search must start at ply 0");
         println!("Searching\n{}", self.root);
+
+        let state = self.game_state();
+        if state != GameState::Ongoing { println!("info string {}", state) }
+
         self.timer.start(self.root.to_move);
+        self.node_count = 0;
+        self.nodes.store(0, Ordering::Relaxed);
+        self.tb_hits = 0;
+        self.completed_depth = 0;
+        self.stopped = false;
+        self.init_root_moves();
+
+        if let Some(mv) = self.book_move() {
+            println!("info string Book move");
+            return mv
+        }
+
+        self.filter_root_moves();
+        self.table.new_search();
+
+        // The helpers search until the main thread has finished, sharing only the table
+        let helpers_stop = Arc::new(AtomicBool::new(false));
+        let helpers: Vec<_> = (1..self.settings.threads).map(|id| {
+            let mut helper = self.helper(id, helpers_stop.clone());
+            thread::spawn(move || {
+                helper.help();
+                helper
+            })
+        }).collect();
+
+        let multi_pv = min(self.settings.multi_pv, self.root_moves.len());
         let mut depth = 1;
 
         while self.timer.should_search(depth) {
-            self.extend();
-            let root = self.root; // Needed due to lexical borrowing (which will be resolved)
-            let score = self.search(&root, depth as u8, -INFINITY, INFINITY, NT::Root);
+            // The partial iteration is discarded, other than the best root move it may have found
+            if !self.iterate(depth, multi_pv) || multi_pv == 0 { break }
+
+            self.timer.toc(self.nodes.load(Ordering::Relaxed), self.root_moves[0].score);
+
+            for i in 0..multi_pv {
+                self.print_line(depth, i, "");
+            }
+
+            depth += 1;
+
+            if let Some(moves) = self.timer.mate() {
+                if self.root_moves[0].score >= VALUE_MATE - (2 * moves as i32 - 1) { break }
+            }
+        }
+
+        helpers_stop.store(true, Ordering::SeqCst);
+        let mut best_depth = self.completed_depth;
+        let mut helper_move = None;
+
+        for helper in helpers {
+            let helper = helper.join().expect("A helper thread panicked");
 
-            self.timer.toc(self.node_count);
-            let pv = self.table.pv(&self.root);
-            let pv_str: Vec<String> = pv.iter().map(Move::to_string).collect();
+            // Prefer a helper which has completed a deeper search, unless showing several lines
+            if multi_pv == 1 && helper.completed_depth > best_depth {
+                helper_move = Some(helper.root_moves[0].clone());
+                best_depth = helper.completed_depth;
+            }
+        }
+
+        // The helper's line is printed too, so that the best move comes with its own score and pv
+        if let Some(root_move) = helper_move {
+            self.root_moves.retain(|other| other.mv != root_move.mv);
+            self.root_moves.insert(0, root_move);
+            self.print_line(best_depth, 0, "");
+        }
+
+        println!("info hashfull {}", self.table.hashfull());
+        self.root_moves.first().map_or(Move::NULL, |root_move| root_move.mv)
+    }
+
+    /// Print the line of a multipv index, where a score which is only a bound is marked as
+    /// " lowerbound" or " upperbound". A root in the tablebases shows its result, unless the
+    /// search has found a mate
+    fn print_line(&self, depth: usize, i: usize, bound: &str) {
+        let root_move = &self.root_moves[i];
+        let pv_str: Vec<String> = root_move.pv.iter().map(Move::to_string).collect();
+        let score = match self.tb_score {
+            Some(tb_score) if root_move.score.abs() < MATE_BOUND => tb_score,
+            _ => root_move.score
+        };
+
+        println!("info depth {} multipv {} score {}{} time {} nodes {} tbhits {} pv {}",
+            depth, i + 1, score_str(score), bound, (self.timer.elapsed() * 1000.0) as u32,
+            self.nodes.load(Ordering::Relaxed), self.tb_hits, pv_str.join(" "));
+    }
+
+    /// Search every multipv line to the given depth, returning whether the iteration completed.
+    /// Each line is first searched with a window around its previous score, which is widened
+    /// on the side the score falls outside of until the score is exact
+    pub fn iterate(&mut self, depth: usize, multi_pv: usize) -> bool {
+        self.root_depth = depth;
+
+        for pv_idx in 0..multi_pv {
+            self.pv_idx = pv_idx;
+
+            let prev_score = self.root_moves[pv_idx].score;
+            let mut delta = ASPIRATION_DELTA;
+            let (mut alpha, mut beta) = if depth >= 4 && prev_score.abs() < MATE_BOUND {
+                (prev_score - delta, prev_score + delta)
+            } else {
+                (-INFINITY, INFINITY)
+            };
+
+            loop {
+                for root_move in &mut self.root_moves[pv_idx..] {
+                    root_move.score = -INFINITY;
+                }
+
+                let score = self.search_root(depth as u8, alpha, beta);
+
+                // Moves which have not been searched keep their place, behind those that have
+                self.root_moves[pv_idx..].sort_by(|a, b| b.score.cmp(&a.score));
+                if self.stopped { return false }
+
+                let bound = if score <= alpha {
+                    alpha = max(score.saturating_sub(delta), -INFINITY);
+                    if pv_idx == 0 { self.timer.fail_low(prev_score) }
+                    " upperbound"
+                } else if score >= beta {
+                    beta = score.saturating_add(delta);
+                    " lowerbound"
+                } else {
+                    break
+                };
+
+                if self.thread_id == 0 { self.print_line(depth, pv_idx, bound) }
+                delta += delta / 2;
+            }
+
+            // A later line may score above an earlier one, so the lines are kept in order of score
+            self.root_moves[..pv_idx + 1].sort_by(|a, b| b.score.cmp(&a.score));
+        }
+
+        self.completed_depth = depth;
+        true
+    }
 
-            println!("info depth {} score cp {} time {} nodes {} pv {}",
-                depth, score / 10, (self.timer.elapsed() * 1000.0) as u32,
-                self.node_count, pv_str.join(" "));
+    /// Iteratively deepen as a helper thread. Odd helpers stay one ply ahead of even ones,
+    /// so that the threads diverge and fill the table with different work
+    pub fn help(&mut self) {
+        self.timer.start(self.root.to_move);
+        let mut depth = 1 + self.thread_id % 2;
 
+        while depth <= MAX_DEPTH && !self.root_moves.is_empty() && self.iterate(depth, 1) {
             depth += 1;
         }
+    }
+
+    /// Search every root move from the current multipv index onwards, recording each
+    /// of their scores and principal variations. Moves which fail low are given -INFINITY
+    pub fn search_root(&mut self, depth: u8, mut alpha: i32, beta: i32) -> i32 {
+        let root = self.root;
+        let mut best_value = -INFINITY;
+
+        for i in self.pv_idx..self.root_moves.len() {
+            let mv = self.root_moves[i].mv;
+            let mut new_board = root;
+            new_board.make_move(mv);
+
+            self.moved[self.ply] = PieceTo::of(&root, mv);
+            self.line[self.ply] = mv;
+            self.ply += 1;
+            self.record_position(new_board.hash);
+
+            let score = if self.is_draw(&new_board) {
+                // The draw isn't searched, so the next ply would keep the variation of another move
+                self.pv_table[self.ply].clear();
+                0
+            } else if i == self.pv_idx {
+                -self.search(&new_board, depth - 1, -beta, -alpha, NT::PV)
+            } else {
+                let mut s = -self.search(&new_board, depth - 1, -(alpha+1), -alpha, NT::NonPV);
+                if s > alpha && s < beta {
+                    s = -self.search(&new_board, depth - 1, -beta, -alpha, NT::PV);
+                }
+                s
+            };
+            self.ply -= 1;
+            if self.stopped { return 0 }
+
+            let root_move = &mut self.root_moves[i];
+
+            if i == self.pv_idx || score > alpha {
+                root_move.score = score;
+                root_move.pv.truncate(1);
+                root_move.pv.extend_from_slice(&self.pv_table[1]);
+            }
+
+            if score > best_value {
+                best_value = score;
+                if score >= beta { return score }
+                alpha = max(alpha, score);
+            }
+        }
+        best_value
+    }
+
+    /// Store the principal variation for the current ply, being the move followed by the
+    /// principal variation of the next ply
+    pub fn update_pv(&mut self, mv: Move) {
+        let (head, tail) = self.pv_table.split_at_mut(self.ply + 1);
+        let pv = &mut head[self.ply];
 
-        println!("occ {} of {}", self.table.set_ancient(), self.table.size());
+        pv.clear();
+        pv.push(mv);
+        pv.extend_from_slice(&tail[0]);
+    }
 
-        let best = self.table.best_move(self.root.hash);
-        println!("bestmove {}", best.unwrap_or(Move::NULL));
+    /// Check the limits of the search, and flag it to unwind once it has to stop
+    pub fn should_abort(&mut self) -> bool {
+        if self.timer.should_abort(self.node_count, self.nodes.load(Ordering::Relaxed)) {
+            self.stopped = true;
+        }
+        self.stopped
     }
 
     pub fn search(&mut self, board: &Board, depth: u8, mut alpha: i32, beta: i32, nt: NT) -> i32 {
         self.node_count += 1;
-        if board.player_in_check(board.prev_move()) { return INFINITY }
-
-        let (table_score, mut best_move) = self.table.probe(board.hash, depth, alpha, beta);
+        self.nodes.fetch_add(1, Ordering::Relaxed);
+        if self.should_abort() { return 0 }
+        self.pv_table[self.ply].clear();
+
+        // Mate distance pruning. Even mating at the next ply can't improve on a shorter mate
+        alpha = max(alpha, -VALUE_MATE + self.ply as i32);
+        let beta = min(beta, VALUE_MATE - self.ply as i32 - 1);
+        if alpha >= beta { return alpha }
+
+        let is_pv = nt == NT::PV;
+        let excluded = self.excluded[self.ply];
+
+        // The table holds the result of searching every move, so it isn't used without one of them
+        let (table_score, mut best_move) = if excluded == Move::NULL {
+            self.table.probe(board.hash, depth, alpha, beta, self.ply)
+        } else {
+            (None, Move::NULL)
+        };
 
+        // Cutoffs aren't taken in PV nodes, which would otherwise truncate the principal variation
         if let Some(s) = table_score {
-            return s
+            if !is_pv { return s }
         }
 
-        if depth == 0 {
-            let score = self.q_search(board, 8, alpha, beta);
+        // Just after a capture or pawn move, positions in the tablebases have a known result
+        if board.halfmove == 0 && excluded == Move::NULL && self.tablebases.can_probe(board) {
+            if let Some(wdl) = self.tablebases.probe_wdl(board) {
+                self.tb_hits += 1;
+
+                let (score, bound) = match wdl {
+                    WDL_WIN  => (TB_WIN - self.ply as i32, Bound::Lower),
+                    WDL_LOSS => (-TB_WIN + self.ply as i32, Bound::Upper),
+                    _ => (0, Bound::Exact)
+                };
 
-            let bound = if score >= beta { Bound::Lower } else if score > alpha { Bound::Exact } else { Bound::Upper };
-            self.table.record(board, score, Move::NULL, depth, bound);
-            return score
+                if    bound == Bound::Exact
+                   || bound == Bound::Lower && score >= beta
+                   || bound == Bound::Upper && score <= alpha
+                {
+                    self.table.record(board, score, Move::NULL, depth.saturating_add(6), bound, self.ply);
+                    return score
+                }
+            }
         }
 
+        if depth == 0 { return self.q_search(board, alpha, beta, true) }
+
         let old_alpha = alpha;
         let mut best_value = -INFINITY;
-        let is_pv = nt == NT::Root || nt == NT::PV;
+        let in_check = board.is_in_check();
+        let eval = if in_check { -INFINITY } else { board.evaluate() };
+        let can_prune = !is_pv && !in_check && excluded == Move::NULL;
+
+        // Reverse futility pruning. Far enough above beta, the opponent is unlikely to recover
+        if    can_prune
+           && depth <= REVERSE_FUTILITY_DEPTH
+           && eval - REVERSE_FUTILITY_MARGIN * depth as i32 >= beta
+           && eval < MATE_BOUND
+        {
+            return eval
+        }
 
-        if    !is_pv
+        // Razoring. Far enough below alpha, only a capture could raise the score
+        if    can_prune
+           && depth <= RAZOR_DEPTH
+           && eval + RAZOR_MARGIN * (depth as i32) <= alpha
+        {
+            let score = self.q_search(board, alpha, alpha + 1, true);
+            if self.stopped { return 0 }
+            if score <= alpha { return score }
+        }
+
+        if    can_prune
            && depth >= 2
-           && !board.is_in_check()
         {
-            let eval = board.evaluate();
             let r = 3 + depth as i32 / 4 + min(max(eval - beta, 0) / p_val(PAWN) as i32, 3);
             let mut new_board = *board;
             new_board.do_null_move();
+            // Repetitions can't be traced back through the null move
+            new_board.halfmove = 0;
 
             let d = if r as u8 >= depth { 0 } else { depth - r as u8 };
+            self.moved[self.ply] = PieceTo::NONE;
+            self.line[self.ply] = Move::NULL;
             self.ply += 1;
-            let s = -self.search(&new_board, d, -beta, -beta+1, NT::PV);
+            self.record_position(new_board.hash);
+            let s = -self.search(&new_board, d, -beta, -beta+1, NT::NonPV);
             self.ply -= 1;
+            if self.stopped { return 0 }
 
             if s >= beta {
-                if s >= VALUE_MATE - 1000 { return beta }
+                if s >= MATE_BOUND { return beta }
 
                 if depth < 14 { return s }
-                let v = self.search(board, d, beta - 1, beta, NT::PV);
+                let v = self.search(board, d, beta - 1, beta, NT::NonPV);
+                if self.stopped { return 0 }
                 if v >= beta { return s }
             }
         }
 
-        let moves = board.sort_with(board.get_moves(), best_move, &self.killers[self.ply]);
+        // Singular extension. When every other move fails well below the table's score for the
+        // hash move, the hash move is forced and is searched a ply deeper
+        let mut singular = false;
+
+        if    depth >= SINGULAR_DEPTH
+           && excluded == Move::NULL
+           && best_move != Move::NULL
+           && board.is_legal(best_move)
+        {
+            if let Some(entry) = self.table.entry(board.hash) {
+                let table_score = value_from_tt(entry.score, self.ply);
+
+                if    entry.bound != Bound::Upper
+                   && entry.best_move == best_move
+                   && entry.depth + 3 >= depth
+                   && table_score.abs() < MATE_BOUND
+                {
+                    let singular_beta = table_score - SINGULAR_MARGIN * depth as i32;
+                    self.excluded[self.ply] = best_move;
+                    let s = self.search(board, depth / 2, singular_beta - 1, singular_beta, NT::NonPV);
+                    self.excluded[self.ply] = Move::NULL;
+                    if self.stopped { return 0 }
+
+                    singular = s < singular_beta;
+                }
+            }
+        }
 
+        let prev_moves = self.prev_moves();
+        let mut picker = MovePicker::new(board, best_move, self.killers[self.ply], prev_moves);
         let mut moves_searched = 0;
+        let mut quiets_tried = Vec::new();
 
-        for (_, mv) in moves {
+        // Quiet moves can't raise a score this far below alpha, unless they give check
+        let futile = !is_pv && !in_check && depth <= FUTILITY_DEPTH
+                  && eval + FUTILITY_MARGIN * depth as i32 <= alpha;
+
+        while let Some(mv) = picker.next(&self.history) {
+            if mv == excluded { continue }
+
+            let is_quiet = !mv.is_capture() && mv.promotion() == 0;
             let mut new_board = *board;
             new_board.make_move(mv);
 
+            // Once a move has avoided being mated, prune quiet moves which are futile or too late
+            if    is_quiet
+               && best_value > -MATE_BOUND
+               && !new_board.is_in_check()
+               && (futile || !is_pv && !in_check && depth <= LATE_MOVE_DEPTH
+                          && moves_searched >= LATE_MOVE_COUNT + depth as usize * depth as usize)
+            {
+                continue
+            }
+
+            let new_depth = depth - 1 + self.extension(board, &new_board, mv, depth, singular && mv == best_move);
+
+            self.moved[self.ply] = PieceTo::of(board, mv);
+            self.line[self.ply] = mv;
             self.ply += 1;
+            self.record_position(new_board.hash);
 
-            let score = if self.check_repitition(new_board.hash) {
+            let score = if self.is_draw(&new_board) {
+                self.pv_table[self.ply].clear();
                 0
             } else if moves_searched == 0 {
-                -self.search(&new_board, depth - 1, -beta, -alpha, NT::PV)
+                -self.search(&new_board, new_depth, -beta, -alpha, nt)
             } else {
                 let mut s = alpha + 1;
 
                 if    depth >= 3
-                   && !mv.is_capture()
-                   && mv.promotion() == 0
+                   && is_quiet
                    && mv != self.killers[self.ply-1].0
                    && mv != self.killers[self.ply-1].1
                    && !new_board.is_in_check()
                 {
-                    // let d = depth - depth / 10 - 2;
-                    let d = depth - depth / 5 - 2;
+                    // Quiet moves which have often caused cutoffs are reduced less, and others more
+                    let history = self.history.score(board, mv, &prev_moves);
+                    let r = 1 + depth as i32 / 5 - history / (HISTORY_MAX / 2);
+                    let d = new_depth - min(max(r, 0), new_depth as i32 - 1) as u8;
                     s = -self.search(&new_board, d, -(alpha+1), -alpha, NT::NonPV);
                 }
 
                 if s > alpha {
-                    s = -self.search(&new_board, depth - 1, -(alpha+1), -alpha, NT::NonPV);
-                    if s > alpha && s < beta {
-                        s = -self.search(&new_board, depth - 1, -beta, -alpha, NT::NonPV);
+                    s = -self.search(&new_board, new_depth, -(alpha+1), -alpha, NT::NonPV);
+                    if is_pv && s > alpha && s < beta {
+                        s = -self.search(&new_board, new_depth, -beta, -alpha, NT::PV);
                     }
                 }
                 s
             };
             self.ply -= 1;
+            if self.stopped { return 0 }
 
-            if score != -INFINITY { moves_searched += 1 } else { continue }
+            moves_searched += 1;
 
             if score > best_value {
                 best_move = mv;
                 best_value = score;
-                if score >= beta {
-                    if !mv.is_capture() { self.killers[self.ply].substitute(mv) }
-                    self.table.record(board, score, mv, depth, Bound::Lower);
-                    return score
+                if score > alpha {
+                    self.update_pv(mv);
+
+                    if score >= beta {
+                        if !mv.is_capture() { self.killers[self.ply].substitute(mv) }
+                        if is_quiet {
+                            self.history.update(board, mv, &quiets_tried, &prev_moves, depth);
+                        }
+                        if excluded == Move::NULL {
+                            self.table.record(board, score, mv, depth, Bound::Lower, self.ply);
+                        }
+                        return score
+                    }
+                    alpha = score;
                 }
-                alpha = max(alpha, score);
             }
+
+            if is_quiet { quiets_tried.push(mv) }
         }
 
         if moves_searched == 0 {
-            if board.is_in_check() {
+            // Only the excluded move, if any, could have been searched
+            if excluded != Move::NULL {
+                return alpha
+            } else if in_check {
                 return -VALUE_MATE + self.ply as i32
             } else {
                 return 0
@@ -229,43 +780,139 @@ impl Searcher {
 
         // let bound = Bound::Upper;
         let bound = if best_value > old_alpha { Bound::Exact } else { Bound::Upper };
-        self.table.record(board, best_value, best_move, depth, bound);
+        if excluded == Move::NULL {
+            self.table.record(board, best_value, best_move, depth, bound, self.ply);
+        }
         best_value
     }
 
-    // TODO: update irreversible, full three move and fifty move repition
-    pub fn check_repitition(&mut self, hash: Hash) -> bool {
-        let mut pos_ply = self.ply + self.root.ply;
-        self.rep[pos_ply] = hash;
+    /// How many plies to extend a move by. Nothing is extended once the search has gone twice
+    /// as deep as the iteration, so that a long series of checks can't grow without limit
+    fn extension(&self, board: &Board, new_board: &Board, mv: Move, depth: u8, singular: bool) -> u8 {
+        if self.ply >= 2 * self.root_depth || self.ply + depth as usize + 2 > MAX_DEPTH { return 0 }
+        if singular || new_board.is_in_check() { return 1 }
+
+        let prev = if self.ply > 0 { self.line[self.ply - 1] } else { Move::NULL };
+        if    self.settings.recapture_extension
+           && mv.is_capture()
+           && prev.is_capture()
+           && mv.to() == prev.to()
+        {
+            return 1
+        }
+
+        // A passed pawn reaching the sixth or seventh rank may be about to promote
+        let (from, to, us) = (mv.from(), mv.to(), board.to_move);
+        let rank = if us == WHITE { to / 8 } else { 7 - to / 8 };
+        if    self.settings.passed_pawn_extension
+           && board.sqs[from as usize] & PIECE == PAWN
+           && rank >= 5
+           && board.is_passed_pawn(to, us)
+        {
+            return 1
+        }
+        0
+    }
+
+    /// The piece and destination of the moves which led to the current ply, the last move first
+    fn prev_moves(&self) -> [PieceTo; 2] {
+        let prev = |n| if self.ply >= n { self.moved[self.ply - n] } else { PieceTo::NONE };
+        [prev(1), prev(2)]
+    }
 
-        let last_index = max(2, self.irreversible);
+    /// Store the hash of the position at the current ply in the game history
+    pub fn record_position(&mut self, hash: Hash) {
+        let pos = self.root_rep + self.ply;
+        self.rep.truncate(pos);
+        self.rep.push(hash);
+    }
 
-        while pos_ply >= last_index {
-            pos_ply -= 2;
-            if self.rep[pos_ply] == hash {
-                return true
+    /// Return whether the position at the current ply is drawn by the fifty move rule or by
+    /// repetition. Repeating any position since the root once is enough to be a draw,
+    /// while positions from before the root have to have been repeated twice. Checkmate on the
+    /// hundredth ply still wins, so a position in check needs a legal move to be drawn
+    pub fn is_draw(&self, board: &Board) -> bool {
+        if board.halfmove >= 100 { return !board.is_in_check() || board.has_legal_move() }
+
+        let pos = self.root_rep + self.ply;
+        // Only positions since the last capture or pawn move can be repeated
+        let earliest = pos - min(board.halfmove, pos);
+        let mut repetitions = 0;
+        let mut i = pos;
+
+        while i >= earliest + 2 {
+            i -= 2;
+            if self.rep[i] == board.hash {
+                if i > self.root_rep { return true }
+                repetitions += 1;
+                if repetitions >= 2 { return true }
             }
         }
         false
     }
 
-    pub fn q_search(&mut self, board: &Board, depth: u8, mut alpha: i32, beta: i32) -> i32 {
+    /// Search captures until the position is quiet, so that it isn't evaluated in the middle
+    /// of an exchange. In check every evasion is searched instead, as there is no standing pat,
+    /// and the first ply may also search the quiet moves which give check
+    pub fn q_search(&mut self, board: &Board, mut alpha: i32, beta: i32, first_ply: bool) -> i32 {
         self.node_count += 1;
-        if board.player_in_check(board.prev_move()) { return INFINITY }
-        let stand_pat = board.evaluate();
-        if depth == 0 || stand_pat >= beta { return stand_pat }
-        if stand_pat > alpha { alpha = stand_pat }
+        self.nodes.fetch_add(1, Ordering::Relaxed);
+        if self.should_abort() { return 0 }
+
+        let (table_score, hash_move) = self.table.probe(board.hash, 0, alpha, beta, self.ply);
+        if let Some(s) = table_score { return s }
+
+        let in_check = board.is_in_check();
+        let stand_pat = if in_check { -INFINITY } else { board.evaluate() };
+        if stand_pat >= beta { return stand_pat }
+
+        let old_alpha = alpha;
+        let (mut best_value, mut best_move) = (stand_pat, Move::NULL);
+        alpha = max(alpha, stand_pat);
+
+        let mut picker = if in_check {
+            MovePicker::new(board, hash_move, Killer::EMPTY, [PieceTo::NONE; 2])
+        } else {
+            MovePicker::quiescence(board, hash_move, first_ply && self.settings.quiescence_checks)
+        };
 
-        for (_, mv) in board.qsort(&board.get_moves()) {
+        while let Some(mv) = picker.next(&self.history) {
+            let captured = if mv.is_en_passant() { PAWN } else { board.sqs[mv.to() as usize] };
             let mut new_board = *board;
             new_board.make_move(mv);
-            let score = -self.q_search(&new_board, depth - 1, -beta, -alpha);
 
-            if score > alpha {
-                if score >= beta { return score }
-                alpha = score;
+            // Delta pruning. Even winning the captured piece for nothing wouldn't raise alpha,
+            // unless the capture also gives check
+            if    !in_check
+               && mv.promotion() == 0
+               && stand_pat + p_val(captured) as i32 + DELTA_MARGIN <= alpha
+               && !new_board.is_in_check()
+            {
+                continue
+            }
+
+            self.ply += 1;
+            let score = -self.q_search(&new_board, -beta, -alpha, false);
+            self.ply -= 1;
+            if self.stopped { return 0 }
+
+            if score > best_value {
+                best_value = score;
+                if score > alpha {
+                    best_move = mv;
+                    if score >= beta {
+                        self.table.record(board, score, mv, 0, Bound::Lower, self.ply);
+                        return score
+                    }
+                    alpha = score;
+                }
             }
         }
-        alpha
+
+        if in_check && best_value == -INFINITY { return -VALUE_MATE + self.ply as i32 }
+
+        let bound = if best_value > old_alpha { Bound::Exact } else { Bound::Upper };
+        self.table.record(board, best_value, best_move, 0, bound, self.ply);
+        best_value
     }
 }
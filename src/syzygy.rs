@@ -0,0 +1,997 @@
+//! Probing of Syzygy endgame tablebases, for win/draw/loss (WDL) and distance to zeroing (DTZ).
+//! The format is decoded in the same way as the probing code of Stockfish, which documents it.
+//! Tables are read into memory the first time they are probed, after which probing them doesn't lock
+use std::cmp::{min, max};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::PathBuf;
+use std::cell::UnsafeCell;
+use std::sync::Once;
+
+use types::*;
+use util::*;
+use _move::Move;
+use board::Board;
+use magics::king_moves;
+
+/// Return the contents of an `Option`, or return `None` from the enclosing function
+macro_rules! try_opt {
+    ($e:expr) => (match $e { Some(v) => v, None => return None });
+}
+
+/// Results from the point of view of the side to move. Cursed wins and blessed losses
+/// are those which are drawn by the fifty move rule
+pub const WDL_LOSS: i32 = -2;
+pub const WDL_BLESSED_LOSS: i32 = -1;
+pub const WDL_DRAW: i32 = 0;
+pub const WDL_CURSED_WIN: i32 = 1;
+pub const WDL_WIN: i32 = 2;
+
+const WDL_MAGIC: [u8; 4] = [0x71, 0xE8, 0x23, 0x5D];
+const DTZ_MAGIC: [u8; 4] = [0xD7, 0x66, 0x0C, 0xA5];
+
+// Flags of each table
+const STM: u8 = 1;
+const MAPPED: u8 = 2;
+const WIN_PLIES: u8 = 4;
+const LOSS_PLIES: u8 = 8;
+const WIDE: u8 = 16;
+const SINGLE_VALUE: u8 = 128;
+
+const MAX_PIECES: usize = 7;
+
+#[derive(PartialEq, Eq, Debug)]
+enum ProbeError {
+    Missing,
+    /// DTZ tables only store one side to move
+    WrongSide
+}
+
+fn le16(bytes: &[u8], pos: usize) -> u64 {
+    bytes[pos] as u64 | (bytes[pos + 1] as u64) << 8
+}
+
+fn le32(bytes: &[u8], pos: usize) -> u64 {
+    le16(bytes, pos) | le16(bytes, pos + 2) << 16
+}
+
+fn be32(bytes: &[u8], pos: usize) -> u64 {
+    bytes[pos..pos + 4].iter().fold(0, |acc, &b| acc << 8 | b as u64)
+}
+
+fn be64(bytes: &[u8], pos: usize) -> u64 {
+    be32(bytes, pos) << 32 | be32(bytes, pos + 4)
+}
+
+fn rank_of(sq: usize) -> usize { sq >> 3 }
+fn file_of(sq: usize) -> usize { sq & 7 }
+
+/// Distance of a square from the a1-h8 diagonal, positive above it
+fn off_diag(sq: usize) -> i32 {
+    rank_of(sq) as i32 - file_of(sq) as i32
+}
+
+/// Our pieces in the encoding of the tables, with white pieces from 1 to 6 and black from 9 to 14
+fn tb_piece(piece: u8) -> u8 {
+    (piece >> 1) + 1 + if piece & COLOR == WHITE { 0 } else { 8 }
+}
+
+fn dtz_before_zeroing(wdl: i32) -> i32 {
+    match wdl {
+        WDL_WIN          => 1,
+        WDL_CURSED_WIN   => 101,
+        WDL_BLESSED_LOSS => -101,
+        WDL_LOSS         => -1,
+        _ => 0
+    }
+}
+
+/// Identify a material configuration by its count of every piece
+fn material_key(counts: &[usize; 12]) -> u64 {
+    counts.iter().enumerate().fold(0, |key, (piece, &n)| key | (n as u64) << (4 * piece))
+}
+
+fn board_material_key(board: &Board) -> u64 {
+    let mut counts = [0; 12];
+    for (piece, n) in counts.iter_mut().enumerate() {
+        *n = count(board.bb[piece as u8]) as usize;
+    }
+    material_key(&counts)
+}
+
+fn piece_from_char(c: char) -> Option<u8> {
+    match c {
+        'P' => Some(PAWN),
+        'N' => Some(KNIGHT),
+        'B' => Some(BISHOP),
+        'R' => Some(ROOK),
+        'Q' => Some(QUEEN),
+        'K' => Some(KING),
+        _ => None
+    }
+}
+
+/// Decoding information for one side to move of one file of a table
+#[derive(Clone, Default)]
+struct PairsData {
+    flags: u8,
+    pieces: [u8; MAX_PIECES],
+    group_len: [usize; MAX_PIECES + 1],
+    group_idx: [u64; MAX_PIECES + 1],
+    sizeof_block: u64,
+    span: u64,
+    sparse_index_size: u64,
+    blocks_num: u64,
+    block_length_size: u64,
+    min_sym_len: u8,
+    base64: Vec<u64>,
+    symlen: Vec<u8>,
+    lowest_sym: usize,
+    btree: usize,
+    sparse_index: usize,
+    block_length: usize,
+    data: usize,
+    map_idx: [usize; 4]
+}
+
+impl PairsData {
+    fn left(&self, bytes: &[u8], sym: usize) -> usize {
+        let pos = self.btree + 3 * sym;
+        ((bytes[pos + 1] as usize & 0xF) << 8) | bytes[pos] as usize
+    }
+
+    fn right(&self, bytes: &[u8], sym: usize) -> usize {
+        let pos = self.btree + 3 * sym;
+        ((bytes[pos + 2] as usize) << 4) | (bytes[pos + 1] as usize >> 4)
+    }
+
+    /// The number of values a symbol expands to, less one
+    fn set_symlen(&mut self, bytes: &[u8], sym: usize, visited: &mut Vec<bool>) -> u8 {
+        visited[sym] = true;
+        let right = self.right(bytes, sym);
+        if right == 0xFFF { return 0 }
+
+        let left = self.left(bytes, sym);
+        if !visited[left] { self.symlen[left] = self.set_symlen(bytes, left, visited) }
+        if !visited[right] { self.symlen[right] = self.set_symlen(bytes, right, visited) }
+
+        self.symlen[left] + self.symlen[right] + 1
+    }
+
+    /// Read the Huffman and block information, returning the position after it
+    fn set_sizes(&mut self, bytes: &[u8], mut pos: usize) -> usize {
+        self.flags = bytes[pos];
+        pos += 1;
+
+        if self.flags & SINGLE_VALUE != 0 {
+            self.min_sym_len = bytes[pos]; // Here the single value is stored
+            return pos + 1
+        }
+
+        let num_groups = self.group_len.iter().position(|&len| len == 0).unwrap();
+        let tb_size = self.group_idx[num_groups];
+
+        self.sizeof_block = 1 << bytes[pos];
+        self.span = 1 << bytes[pos + 1];
+        self.sparse_index_size = (tb_size + self.span - 1) / self.span;
+        let padding = bytes[pos + 2] as u64;
+        self.blocks_num = le32(bytes, pos + 3);
+        self.block_length_size = self.blocks_num + padding;
+        let max_sym_len = bytes[pos + 7];
+        self.min_sym_len = bytes[pos + 8];
+        pos += 9;
+
+        self.lowest_sym = pos;
+        let num_lengths = (max_sym_len - self.min_sym_len) as usize + 1;
+        self.base64 = vec![0; num_lengths];
+
+        // Symbols with longer codes have lower values, so base64[i] >= base64[i + 1]
+        for i in (0..num_lengths - 1).rev() {
+            self.base64[i] = (self.base64[i + 1] + le16(bytes, pos + 2 * i)
+                              - le16(bytes, pos + 2 * (i + 1))) / 2;
+        }
+
+        // Right pad to 64 bits, so that any code of length i lies in base64[i - 1] >= code >= base64[i]
+        for (i, base) in self.base64.iter_mut().enumerate() {
+            let shift = 64 - i as u32 - self.min_sym_len as u32;
+            *base = if shift < 64 { *base << shift } else { 0 };
+        }
+        pos += 2 * num_lengths;
+
+        let num_syms = le16(bytes, pos) as usize;
+        pos += 2;
+        self.btree = pos;
+        self.symlen = vec![0; num_syms];
+
+        let mut visited = vec![false; num_syms];
+        for sym in 0..num_syms {
+            if !visited[sym] {
+                let len = self.set_symlen(bytes, sym, &mut visited);
+                self.symlen[sym] = len;
+            }
+        }
+
+        pos + 3 * num_syms + (num_syms & 1)
+    }
+
+    /// Find the value stored at a given index, which is compressed by recursive pairing
+    /// into blocks of canonical Huffman codes
+    fn decompress(&self, bytes: &[u8], idx: u64) -> u64 {
+        if self.flags & SINGLE_VALUE != 0 { return self.min_sym_len as u64 }
+
+        // The sparse index gives the block and offset of every span'th value, from the middle
+        let k = idx / self.span;
+        let entry = self.sparse_index + 6 * k as usize;
+        let mut block = le32(bytes, entry) as usize;
+        let mut offset = le16(bytes, entry + 4) as i64;
+        offset += (idx % self.span) as i64 - (self.span / 2) as i64;
+
+        let block_length = |block: usize| le16(bytes, self.block_length + 2 * block) as i64;
+
+        while offset < 0 {
+            block -= 1;
+            offset += block_length(block) + 1;
+        }
+
+        while offset > block_length(block) {
+            offset -= block_length(block) + 1;
+            block += 1;
+        }
+
+        let mut ptr = self.data + block * self.sizeof_block as usize;
+        let mut buf64 = be64(bytes, ptr);
+        let mut buf64_size = 64;
+        ptr += 8;
+
+        let mut sym;
+        loop {
+            let mut len = 0;
+            while buf64 < self.base64[len] { len += 1 }
+
+            sym = ((buf64 - self.base64[len]) >> (64 - len - self.min_sym_len as usize)) as usize;
+            sym += le16(bytes, self.lowest_sym + 2 * len) as usize;
+
+            if offset < self.symlen[sym] as i64 + 1 { break }
+
+            offset -= self.symlen[sym] as i64 + 1;
+            len += self.min_sym_len as usize;
+            buf64 <<= len;
+            buf64_size -= len;
+
+            if buf64_size <= 32 {
+                buf64_size += 32;
+                buf64 |= be32(bytes, ptr) << (64 - buf64_size);
+                ptr += 4;
+            }
+        }
+
+        // Expand the pairs of the symbol until reaching the single value at our offset
+        while self.symlen[sym] != 0 {
+            let left = self.left(bytes, sym);
+
+            if offset < self.symlen[left] as i64 + 1 {
+                sym = left;
+            } else {
+                offset -= self.symlen[left] as i64 + 1;
+                sym = self.right(bytes, sym);
+            }
+        }
+
+        self.left(bytes, sym) as u64
+    }
+}
+
+/// The contents of a table file
+struct TableFile {
+    bytes: Vec<u8>,
+    /// Indexed by the file of the leading pawn (if any), then the side to move
+    items: Vec<Vec<PairsData>>,
+    /// The start of the DTZ value maps
+    map: usize
+}
+
+/// A table file which is read by the first probe that needs it. Later probes only check
+/// that it has been read, without locking
+struct LazyTable {
+    once: Once,
+    table: UnsafeCell<Option<TableFile>>
+}
+
+// The table is only written inside `call_once`, which every other thread waits for
+unsafe impl Sync for LazyTable {}
+
+impl LazyTable {
+    fn new() -> Self {
+        LazyTable { once: Once::new(), table: UnsafeCell::new(None) }
+    }
+
+    /// The table, read by `load` if this is the first time it is needed
+    fn get<F: FnOnce() -> Option<TableFile>>(&self, load: F) -> Option<&TableFile> {
+        self.once.call_once(|| unsafe { *self.table.get() = load() });
+        unsafe { (*self.table.get()).as_ref() }
+    }
+}
+
+/// A material configuration, such as KRvK, for which there are table files
+struct Material {
+    key: u64,
+    key2: u64,
+    piece_count: usize,
+    has_pawns: bool,
+    has_unique_pieces: bool,
+    /// Pawns of the leading color, then the other
+    pawn_count: [usize; 2],
+    wdl_path: Option<PathBuf>,
+    dtz_path: Option<PathBuf>,
+    wdl: LazyTable,
+    dtz: LazyTable
+}
+
+impl Material {
+    /// Parse a name such as KRPvKR, where the pieces before the 'v' are white
+    fn from_code(code: &str) -> Option<Self> {
+        let sides: Vec<&str> = code.split('v').collect();
+        if sides.len() != 2 { return None }
+
+        let mut counts = [0; 12];
+        for (side, color) in sides.iter().zip(&[WHITE, BLACK]) {
+            if !side.starts_with('K') { return None }
+
+            for c in side.chars() {
+                match piece_from_char(c) {
+                    Some(piece) => counts[(piece | color) as usize] += 1,
+                    None => return None
+                }
+            }
+        }
+
+        let mut flipped = [0; 12];
+        for (piece, &n) in counts.iter().enumerate() {
+            flipped[piece ^ COLOR as usize] = n;
+        }
+
+        let piece_count: usize = counts.iter().sum();
+        if piece_count > MAX_PIECES || counts[(KING | WHITE) as usize] != 1 ||
+           counts[(KING | BLACK) as usize] != 1 { return None }
+
+        let pawns = [counts[(PAWN | WHITE) as usize], counts[(PAWN | BLACK) as usize]];
+        let has_unique_pieces = (0..12).any(|piece| piece & PIECE as usize != KING as usize && counts[piece] == 1);
+
+        // The leading color is the side with fewer pawns, as it compresses better
+        let white_leads = pawns[1] == 0 || (pawns[0] != 0 && pawns[1] >= pawns[0]);
+        let pawn_count = if white_leads { pawns } else { [pawns[1], pawns[0]] };
+
+        Some(Material {
+            key: material_key(&counts),
+            key2: material_key(&flipped),
+            piece_count: piece_count,
+            has_pawns: pawns[0] + pawns[1] > 0,
+            has_unique_pieces: has_unique_pieces,
+            pawn_count: pawn_count,
+            wdl_path: None,
+            dtz_path: None,
+            wdl: LazyTable::new(),
+            dtz: LazyTable::new()
+        })
+    }
+}
+
+/// The tables found in the given directories, along with the constants of the index encoding
+pub struct Tablebases {
+    materials: Vec<Material>,
+    by_key: HashMap<u64, usize>,
+    pub max_pieces: usize,
+    binomial: [[u64; 64]; MAX_PIECES],
+    lead_pawn_idx: [[u64; 64]; MAX_PIECES],
+    lead_pawns_size: [[u64; 4]; MAX_PIECES],
+    map_pawns: [usize; 64],
+    map_b1h1h7: [u64; 64],
+    map_a1d1d4: [usize; 64],
+    map_kk: [[u64; 64]; 10]
+}
+
+impl Tablebases {
+    /// No tables at all, so that nothing is ever probed
+    pub fn empty() -> Self {
+        Tablebases {
+            materials: Vec::new(),
+            by_key: HashMap::new(),
+            max_pieces: 0,
+            binomial: [[0; 64]; MAX_PIECES],
+            lead_pawn_idx: [[0; 64]; MAX_PIECES],
+            lead_pawns_size: [[0; 4]; MAX_PIECES],
+            map_pawns: [0; 64],
+            map_b1h1h7: [0; 64],
+            map_a1d1d4: [0; 64],
+            map_kk: [[0; 64]; 10]
+        }
+    }
+
+    /// Find every table in a list of directories, separated as in the PATH variable
+    pub fn new(paths: &str) -> Self {
+        let mut tb = Tablebases::empty();
+        tb.init_maps();
+        let separator = if cfg!(windows) { ';' } else { ':' };
+        let mut codes: HashMap<String, Material> = HashMap::new();
+
+        for dir in paths.split(separator).filter(|dir| !dir.is_empty()) {
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(_) => continue
+            };
+
+            for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+                let (code, is_dtz) = match (path.file_stem().and_then(|s| s.to_str()),
+                                            path.extension().and_then(|s| s.to_str())) {
+                    (Some(code), Some("rtbw")) => (code.to_string(), false),
+                    (Some(code), Some("rtbz")) => (code.to_string(), true),
+                    _ => continue
+                };
+
+                if !codes.contains_key(&code) {
+                    match Material::from_code(&code) {
+                        Some(material) => { codes.insert(code.clone(), material); },
+                        None => continue
+                    }
+                }
+
+                let material = codes.get_mut(&code).unwrap();
+                if is_dtz { material.dtz_path = Some(path) } else { material.wdl_path = Some(path) }
+            }
+        }
+
+        for (_, material) in codes {
+            // Only tables with WDL files can be used in the search
+            if material.wdl_path.is_none() { continue }
+
+            tb.max_pieces = max(tb.max_pieces, material.piece_count);
+            tb.by_key.insert(material.key, tb.materials.len());
+            tb.by_key.insert(material.key2, tb.materials.len());
+            tb.materials.push(material);
+        }
+        tb
+    }
+
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
+    fn init_maps(&mut self) {
+        // map_b1h1h7 encodes squares below the a1-h8 diagonal to 0..27
+        let mut code = 0;
+        for sq in 0..64 {
+            if off_diag(sq) < 0 {
+                self.map_b1h1h7[sq] = code;
+                code += 1;
+            }
+        }
+
+        // map_a1d1d4 encodes the a1-d1-d4 triangle to 0..9, with the diagonal last
+        let mut diagonal = Vec::new();
+        let mut code = 0;
+        for sq in 0..28 {
+            if off_diag(sq) < 0 && file_of(sq) <= 3 {
+                self.map_a1d1d4[sq] = code;
+                code += 1;
+            } else if off_diag(sq) == 0 && file_of(sq) <= 3 {
+                diagonal.push(sq);
+            }
+        }
+        for sq in diagonal {
+            self.map_a1d1d4[sq] = code;
+            code += 1;
+        }
+
+        // map_kk encodes the 462 legal placements of two kings with the first in the
+        // a1-d1-d4 triangle, and the second not above the diagonal if the first is on it
+        let mut both_on_diagonal = Vec::new();
+        let mut code = 0;
+        for idx in 0..10 {
+            for s1 in 0..28 {
+                if self.map_a1d1d4[s1] != idx || (idx == 0 && s1 != 1) { continue }
+
+                for s2 in 0..64 {
+                    if (king_moves(s1 as u32) | 1 << s1) & 1 << s2 != 0 { continue }
+
+                    if off_diag(s1) == 0 && off_diag(s2) > 0 { continue }
+
+                    if off_diag(s1) == 0 && off_diag(s2) == 0 {
+                        both_on_diagonal.push((idx, s2));
+                    } else {
+                        self.map_kk[idx][s2] = code;
+                        code += 1;
+                    }
+                }
+            }
+        }
+        for (idx, s2) in both_on_diagonal {
+            self.map_kk[idx][s2] = code;
+            code += 1;
+        }
+
+        self.binomial[0][0] = 1;
+        for n in 1..64 {
+            for k in 0..min(MAX_PIECES, n + 1) {
+                self.binomial[k][n] = if k > 0 { self.binomial[k - 1][n - 1] } else { 0 }
+                                    + if k < n { self.binomial[k][n - 1] } else { 0 };
+            }
+        }
+
+        // map_pawns encodes a2-h7 to 0..47, such that the leading pawn, nearest the
+        // edge and lowest, has the largest value
+        let mut available = 47;
+        for lead_pawns in 1..MAX_PIECES - 1 {
+            for f in 0..4 {
+                let mut idx = 0;
+
+                for r in 1..7 {
+                    let sq = 8 * r + f;
+
+                    if lead_pawns == 1 {
+                        self.map_pawns[sq] = available;
+                        self.map_pawns[sq ^ 7] = available - 1;
+                        available -= 2;
+                    }
+                    self.lead_pawn_idx[lead_pawns][sq] = idx;
+                    idx += self.binomial[lead_pawns - 1][self.map_pawns[sq]];
+                }
+                self.lead_pawns_size[lead_pawns][f] = idx;
+            }
+        }
+    }
+
+    /// Read and parse a table file, if there is one
+    fn load(&self, material: &Material, dtz: bool) -> Option<TableFile> {
+        let path = try_opt!(if dtz { material.dtz_path.as_ref() } else { material.wdl_path.as_ref() });
+        let mut bytes = Vec::new();
+        let read = File::open(path).and_then(|mut file| file.read_to_end(&mut bytes)).ok();
+
+        let table = read.and_then(|_| self.parse(material, bytes, dtz));
+        if table.is_none() { println!("info string Could not read tablebase {}", path.display()) }
+        table
+    }
+
+    fn parse(&self, material: &Material, bytes: Vec<u8>, dtz: bool) -> Option<TableFile> {
+        let magic = if dtz { DTZ_MAGIC } else { WDL_MAGIC };
+        if bytes.len() < 6 || bytes[..4] != magic { return None }
+
+        let split = bytes[4] & 1 != 0;
+        let has_pawns = bytes[4] & 2 != 0;
+        if has_pawns != material.has_pawns || split != (material.key != material.key2) { return None }
+
+        let sides = if !dtz && split { 2 } else { 1 };
+        let num_files = if has_pawns { 4 } else { 1 };
+        let both_pawns = has_pawns && material.pawn_count[1] > 0;
+        let mut items = vec![vec![PairsData::default(); sides]; num_files];
+        let mut pos = 5;
+
+        for f in 0..num_files {
+            let second = if both_pawns { bytes[pos + 1] } else { 0xFF };
+            let order = [[(bytes[pos] & 0xF) as usize, (second & 0xF) as usize],
+                         [(bytes[pos] >> 4) as usize, (second >> 4) as usize]];
+            pos += if both_pawns { 2 } else { 1 };
+
+            for k in 0..material.piece_count {
+                for (i, d) in items[f].iter_mut().enumerate() {
+                    d.pieces[k] = if i == 0 { bytes[pos] & 0xF } else { bytes[pos] >> 4 };
+                }
+                pos += 1;
+            }
+
+            for (i, d) in items[f].iter_mut().enumerate() {
+                self.set_groups(material, d, order[i], f);
+            }
+        }
+
+        pos += pos & 1;
+
+        for d in items.iter_mut().flat_map(|sides| sides.iter_mut()) {
+            pos = d.set_sizes(&bytes, pos);
+        }
+
+        let map = pos;
+        if dtz {
+            for sides in items.iter_mut() {
+                let d = &mut sides[0];
+                if d.flags & MAPPED == 0 { continue }
+
+                if d.flags & WIDE != 0 {
+                    pos += pos & 1;
+                    for i in 0..4 {
+                        d.map_idx[i] = (pos - map) / 2 + 1;
+                        pos += 2 * le16(&bytes, pos) as usize + 2;
+                    }
+                } else {
+                    for i in 0..4 {
+                        d.map_idx[i] = pos - map + 1;
+                        pos += bytes[pos] as usize + 1;
+                    }
+                }
+            }
+            pos += pos & 1;
+        }
+
+        for d in items.iter_mut().flat_map(|sides| sides.iter_mut()) {
+            d.sparse_index = pos;
+            pos += 6 * d.sparse_index_size as usize;
+        }
+
+        for d in items.iter_mut().flat_map(|sides| sides.iter_mut()) {
+            d.block_length = pos;
+            pos += 2 * d.block_length_size as usize;
+        }
+
+        for d in items.iter_mut().flat_map(|sides| sides.iter_mut()) {
+            pos = (pos + 0x3F) & !0x3F;
+            d.data = pos;
+            pos += (d.blocks_num * d.sizeof_block) as usize;
+        }
+
+        if pos > bytes.len() { return None }
+        Some(TableFile { bytes: bytes, items: items, map: map })
+    }
+
+    /// Split the pieces into groups, which are encoded in the given order.
+    /// The leading group is the kings with any unique piece, or the leading pawns
+    fn set_groups(&self, material: &Material, d: &mut PairsData, order: [usize; 2], f: usize) {
+        let mut n = 0;
+        let mut first_len: i32 = if material.has_pawns { 0 } else if material.has_unique_pieces { 3 } else { 2 };
+        d.group_len[0] = 1;
+
+        for i in 1..material.piece_count {
+            first_len -= 1;
+            if first_len > 0 || d.pieces[i] == d.pieces[i - 1] {
+                d.group_len[n] += 1;
+            } else {
+                n += 1;
+                d.group_len[n] = 1;
+            }
+        }
+        n += 1;
+        d.group_len[n] = 0;
+
+        let both_pawns = material.has_pawns && material.pawn_count[1] > 0;
+        let mut next = if both_pawns { 2 } else { 1 };
+        let mut free_squares = 64 - d.group_len[0] - if both_pawns { d.group_len[1] } else { 0 };
+        let mut idx = 1;
+        let mut k = 0;
+
+        while next < n || k == order[0] || k == order[1] {
+            if k == order[0] {
+                d.group_idx[0] = idx;
+                idx *= if material.has_pawns { self.lead_pawns_size[d.group_len[0]][f] }
+                       else if material.has_unique_pieces { 31332 } else { 462 };
+            } else if k == order[1] {
+                d.group_idx[1] = idx;
+                idx *= self.binomial[d.group_len[1]][48 - d.group_len[0]];
+            } else {
+                d.group_idx[next] = idx;
+                idx *= self.binomial[d.group_len[next]][free_squares];
+                free_squares -= d.group_len[next];
+                next += 1;
+            }
+            k += 1;
+        }
+        d.group_idx[n] = idx;
+    }
+
+    /// Look up the position in its table, which gives a WDL value, or for DTZ tables
+    /// the distance to zeroing given the WDL value of the position
+    fn probe_table(&self, board: &Board, dtz: bool, wdl: i32) -> Result<i32, ProbeError> {
+        let all = board.bb[ALL | WHITE] | board.bb[ALL | BLACK];
+        if count(all) == 2 { return Ok(0) } // Only kings are a draw
+
+        let key = board_material_key(board);
+        let material = match self.by_key.get(&key) {
+            Some(&index) => &self.materials[index],
+            None => return Err(ProbeError::Missing)
+        };
+        let lazy = if dtz { &material.dtz } else { &material.wdl };
+        let table = match lazy.get(|| self.load(material, dtz)) {
+            Some(table) => table,
+            None => return Err(ProbeError::Missing)
+        };
+
+        let (tb_file, side, idx) = try!(self.encode(material, table, board, dtz));
+        let value = table.items[tb_file][side].decompress(&table.bytes, idx) as i32;
+        Ok(if dtz { self.map_dtz(table, tb_file, value, wdl) } else { value - 2 })
+    }
+
+    /// Compute the index of a position in a table, along with the file and side
+    /// to move of the part of the table which holds it
+    fn encode(&self, material: &Material, table: &TableFile, board: &Board, dtz: bool)
+        -> Result<(usize, usize, u64), ProbeError>
+    {
+        let all = board.bb[ALL | WHITE] | board.bb[ALL | BLACK];
+        let key = board_material_key(board);
+
+        // Tables are stored with white as the stronger side, and symmetric tables
+        // only with white to move, so otherwise we flip the colors and squares
+        let flip = (material.key == material.key2 && board.to_move == BLACK) || key != material.key;
+        let flip_color = if flip { 8 } else { 0 };
+        let flip_squares = if flip { 56 } else { 0 };
+        let stm = flip as usize ^ if board.to_move == WHITE { 0 } else { 1 };
+
+        let mut squares = Vec::with_capacity(MAX_PIECES);
+        let mut pieces = Vec::with_capacity(MAX_PIECES);
+        let mut lead_pawns = 0;
+        let mut tb_file = 0;
+
+        // Tables with pawns are split by the file of the leading pawn, which
+        // has the largest map_pawns value
+        if material.has_pawns {
+            let pc = table.items[0][0].pieces[0] ^ flip_color;
+            let color = if pc < 8 { WHITE } else { BLACK };
+            lead_pawns = board.bb[PAWN | color];
+
+            for_all(lead_pawns, &mut |sq| {
+                squares.push(sq as usize ^ flip_squares);
+                pieces.push(pc);
+            });
+
+            let lead = (0..squares.len()).fold(0, |best, i|
+                if self.map_pawns[squares[i]] > self.map_pawns[squares[best]] { i } else { best });
+            squares.swap(0, lead);
+
+            tb_file = min(file_of(squares[0]), file_of(squares[0] ^ 7));
+        }
+        let lead_count = squares.len();
+
+        let sides = table.items[tb_file].len();
+        let d = &table.items[tb_file][stm % sides];
+
+        if dtz && (d.flags & STM) as usize != stm && !(material.key == material.key2 && !material.has_pawns) {
+            return Err(ProbeError::WrongSide)
+        }
+
+        for_all(all ^ lead_pawns, &mut |sq| {
+            squares.push(sq as usize ^ flip_squares);
+            pieces.push(tb_piece(board.sqs[sq as usize]) ^ flip_color);
+        });
+        let size = squares.len();
+
+        // Order the pieces in the sequence of the table
+        for i in lead_count..size - 1 {
+            for j in i + 1..size {
+                if d.pieces[i] == pieces[j] {
+                    pieces.swap(i, j);
+                    squares.swap(i, j);
+                    break
+                }
+            }
+        }
+
+        // Mirror so that the leading piece is on files a-d
+        if file_of(squares[0]) > 3 {
+            for sq in squares.iter_mut() { *sq ^= 7 }
+        }
+
+        let mut idx;
+        if material.has_pawns {
+            idx = self.lead_pawn_idx[lead_count][squares[0]];
+
+            let map_pawns = &self.map_pawns;
+            squares[1..lead_count].sort_by_key(|&sq| map_pawns[sq]);
+
+            for i in 1..lead_count {
+                idx += self.binomial[i][self.map_pawns[squares[i]]];
+            }
+        } else {
+            // Mirror so that the leading piece is on ranks 1-4, then below the diagonal
+            if rank_of(squares[0]) > 3 {
+                for sq in squares.iter_mut() { *sq ^= 56 }
+            }
+
+            for i in 0..d.group_len[0] {
+                if off_diag(squares[i]) == 0 { continue }
+
+                if off_diag(squares[i]) > 0 {
+                    for sq in squares[i..].iter_mut() {
+                        *sq = ((*sq >> 3) | (*sq << 3)) & 63;
+                    }
+                }
+                break
+            }
+
+            idx = self.encode_leading(material, &squares);
+        }
+
+        idx *= d.group_idx[0];
+
+        let mut group_start = d.group_len[0];
+        let mut remaining_pawns = material.has_pawns && material.pawn_count[1] > 0;
+        let mut next = 1;
+
+        while d.group_len[next] != 0 {
+            let len = d.group_len[next];
+            squares[group_start..group_start + len].sort();
+
+            let mut n = 0;
+            for i in 0..len {
+                let sq = squares[group_start + i];
+                // Squares of earlier groups can't be occupied, so they are skipped
+                let adjust = squares[..group_start].iter().filter(|&&s| sq > s).count();
+                n += self.binomial[i + 1][sq - adjust - if remaining_pawns { 8 } else { 0 }];
+            }
+
+            remaining_pawns = false;
+            idx += n * d.group_idx[next];
+            group_start += len;
+            next += 1;
+        }
+
+        Ok((tb_file, stm % sides, idx))
+    }
+
+    /// Encode the leading group of a table without pawns, which is either the kings
+    /// or the kings along with a unique piece
+    fn encode_leading(&self, material: &Material, squares: &[usize]) -> u64 {
+        if !material.has_unique_pieces {
+            return self.map_kk[self.map_a1d1d4[squares[0]]][squares[1]]
+        }
+
+        let (s0, s1, s2) = (squares[0] as u64, squares[1] as u64, squares[2] as u64);
+        let adjust1 = (s1 > s0) as u64;
+        let adjust2 = (s2 > s0) as u64 + (s2 > s1) as u64;
+        let rank = |sq: u64| sq >> 3;
+
+        if off_diag(squares[0]) != 0 {
+            (self.map_a1d1d4[squares[0]] as u64 * 63 + (s1 - adjust1)) * 62 + s2 - adjust2
+        } else if off_diag(squares[1]) != 0 {
+            (6 * 63 + rank(s0) * 28 + self.map_b1h1h7[squares[1]]) * 62 + s2 - adjust2
+        } else if off_diag(squares[2]) != 0 {
+            6 * 63 * 62 + 4 * 28 * 62 + rank(s0) * 7 * 28 + (rank(s1) - adjust1) * 28
+            + self.map_b1h1h7[squares[2]]
+        } else {
+            6 * 63 * 62 + 4 * 28 * 62 + 4 * 7 * 28 + rank(s0) * 7 * 6 + (rank(s1) - adjust1) * 6
+            + (rank(s2) - adjust2)
+        }
+    }
+
+    /// Convert a stored DTZ value to plies
+    fn map_dtz(&self, table: &TableFile, f: usize, value: i32, wdl: i32) -> i32 {
+        const WDL_MAP: [usize; 5] = [1, 3, 0, 2, 0];
+        let d = &table.items[f][0];
+        let mut value = value;
+
+        if d.flags & MAPPED != 0 {
+            let idx = d.map_idx[WDL_MAP[(wdl + 2) as usize]] + value as usize;
+            value = if d.flags & WIDE != 0 { le16(&table.bytes, table.map + 2 * idx) as i32 }
+                    else { table.bytes[table.map + idx] as i32 };
+        }
+
+        // Values may be stored in moves rather than plies
+        if (wdl == WDL_WIN && d.flags & WIN_PLIES == 0) || (wdl == WDL_LOSS && d.flags & LOSS_PLIES == 0)
+            || wdl == WDL_CURSED_WIN || wdl == WDL_BLESSED_LOSS {
+            value *= 2;
+        }
+        value + 1
+    }
+
+    /// Probe the WDL value, resolving captures by search, as tables may store any value
+    /// for positions where a capture is best. Also return whether the best move is zeroing,
+    /// in which case the DTZ table does not hold a meaningful value
+    fn search(&self, board: &Board, check_zeroing: bool) -> Option<(i32, bool)> {
+        let moves = board.legal_moves();
+        let mut best = WDL_LOSS;
+        let mut move_count = 0;
+
+        for &mv in &moves {
+            let is_pawn = board.sqs[mv.from() as usize] & PIECE == PAWN;
+            if !mv.is_capture() && (!check_zeroing || !is_pawn) { continue }
+
+            move_count += 1;
+            let mut new_board = *board;
+            new_board.make_move(mv);
+            let value = -try_opt!(self.search(&new_board, false)).0;
+
+            if value > best {
+                best = value;
+                if value >= WDL_WIN { return Some((value, true)) }
+            }
+        }
+
+        // If every move has been searched, the stored value may be wrong, as with en passant
+        let no_more_moves = move_count > 0 && move_count == moves.len();
+
+        let value = if no_more_moves { best } else {
+            match self.probe_table(board, false, WDL_DRAW) {
+                Ok(value) => value,
+                Err(_) => return None
+            }
+        };
+
+        if best >= value {
+            return Some((best, best > WDL_DRAW || no_more_moves))
+        }
+        Some((value, false))
+    }
+
+    /// Whether a position can be probed at all. Tables don't include castling rights
+    pub fn can_probe(&self, board: &Board) -> bool {
+        let pieces = count(board.bb[ALL | WHITE] | board.bb[ALL | BLACK]) as usize;
+        pieces <= self.max_pieces && board.castling == 0
+    }
+
+    pub fn probe_wdl(&self, board: &Board) -> Option<i32> {
+        self.search(board, false).map(|(wdl, _)| wdl)
+    }
+
+    /// The number of plies until the next capture or pawn move, signed by the result,
+    /// with 100 added for results which are drawn by the fifty move rule
+    pub fn probe_dtz(&self, board: &Board) -> Option<i32> {
+        let (wdl, zeroing) = try_opt!(self.search(board, true));
+
+        if wdl == WDL_DRAW { return Some(0) }
+        if zeroing { return Some(dtz_before_zeroing(wdl)) }
+
+        match self.probe_table(board, true, wdl) {
+            Ok(dtz) => {
+                let cursed = if wdl == WDL_CURSED_WIN || wdl == WDL_BLESSED_LOSS { 100 } else { 0 };
+                return Some((dtz + cursed) * wdl.signum())
+            },
+            Err(ProbeError::WrongSide) => (),
+            Err(ProbeError::Missing) => return None
+        }
+
+        // The table holds the other side to move, so search one ply for the best dtz
+        let mut min_dtz = 0xFFFF;
+
+        for mv in board.legal_moves() {
+            let zeroing = mv.is_capture() || board.sqs[mv.from() as usize] & PIECE == PAWN;
+            let mut new_board = *board;
+            new_board.make_move(mv);
+
+            let mut dtz = if zeroing { -dtz_before_zeroing(try_opt!(self.search(&new_board, false)).0) }
+                          else { -try_opt!(self.probe_dtz(&new_board)) };
+
+            // A mating move has a dtz of 1
+            if dtz == 1 && new_board.is_in_check() && new_board.legal_moves().is_empty() {
+                min_dtz = 1;
+            }
+
+            if !zeroing { dtz += dtz.signum() }
+
+            if dtz < min_dtz && dtz.signum() == wdl.signum() {
+                min_dtz = dtz;
+            }
+        }
+
+        // Without legal moves, this is mate
+        Some(if min_dtz == 0xFFFF { -1 } else { min_dtz })
+    }
+
+    /// Rank each root move by its DTZ result, preferring faster wins and slower losses
+    /// while taking the fifty move rule into account. Higher ranks are better
+    pub fn rank_root_moves(&self, board: &Board, moves: &[Move]) -> Option<Vec<i32>> {
+        let halfmove = board.halfmove as i32;
+        let mut ranks = Vec::with_capacity(moves.len());
+
+        for &mv in moves {
+            let mut new_board = *board;
+            new_board.make_move(mv);
+
+            let mut dtz = if new_board.halfmove == 0 {
+                dtz_before_zeroing(-try_opt!(self.probe_wdl(&new_board)))
+            } else {
+                let dtz = -try_opt!(self.probe_dtz(&new_board));
+                dtz + dtz.signum()
+            };
+
+            if dtz == 2 && new_board.is_in_check() && new_board.legal_moves().is_empty() {
+                dtz = 1;
+            }
+
+            let rank = if dtz > 0 {
+                if dtz + halfmove <= 99 { 1000 } else { 1000 - (dtz + halfmove) }
+            } else if dtz < 0 {
+                if -dtz * 2 + halfmove < 100 { -1000 } else { -1000 + (-dtz + halfmove) }
+            } else {
+                0
+            };
+            ranks.push(rank);
+        }
+        Some(ranks)
+    }
+}
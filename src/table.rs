@@ -1,29 +1,26 @@
-use rand::{Rng, SeedableRng, StdRng};
 use std::collections::HashSet;
+use std::cmp::min;
 use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use types::*;
 use _move::Move;
 use board::*;
+use book::{POLYGLOT_KEYS, CASTLE_KEYS, EP_KEYS, TURN_KEY};
+use search::MATE_BOUND;
 use util::lsb;
 
-static mut piece_keys: [u64; 64*6*2] = [0; 64*6*2];
+/// The key for every combination of castling rights
 static mut castle_keys: [u64; 16] = [0; 16];
-static mut ep_keys: [u64; 8] = [0; 8];
-static mut color_key: u64 = 0;
-
-fn set_random(arr: &mut [u64], rng: &mut StdRng) {
-    for elem in arr.iter_mut() {
-        *elem = rng.gen();
-    }
-}
 
+/// The hash keys are those of Polyglot, so that our hash is also the key for the opening book
 pub unsafe fn init() {
-    let seed: &[usize] = &[0];
-    let rng = &mut SeedableRng::from_seed(seed);
-    set_random(&mut piece_keys,  rng);
-    set_random(&mut castle_keys, rng);
-    set_random(&mut ep_keys,     rng);
-    color_key = rng.gen();
+    let rights = [WK_CASTLE, WQ_CASTLE, BK_CASTLE, BQ_CASTLE];
+
+    for (castling, key) in castle_keys.iter_mut().enumerate() {
+        for (i, &right) in rights.iter().enumerate() {
+            if castling as u8 & right != 0 { *key ^= POLYGLOT_KEYS[CASTLE_KEYS + i] }
+        }
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
@@ -45,8 +42,7 @@ impl Hash {
 
     pub fn set_piece(&mut self, pos: usize, sq: u8) {
         if sq != EMPTY {
-            let index = pos + ((sq & PIECE) >> 1) as usize * 64 + (sq & COLOR) as usize * 384;
-            self.val ^= unsafe { piece_keys[index] };
+            self.val ^= POLYGLOT_KEYS[64 * sq as usize + pos];
         }
     }
 
@@ -57,77 +53,206 @@ impl Hash {
     pub fn set_ep(&mut self, en_passant: u64) {
         if en_passant != 0 {
             let file = lsb(en_passant) % 8;
-            self.val ^= unsafe { ep_keys[file as usize] };
+            self.val ^= POLYGLOT_KEYS[EP_KEYS + file as usize];
         }
     }
 
     pub fn flip_color(&mut self) {
-        self.val ^= unsafe { color_key };
+        self.val ^= POLYGLOT_KEYS[TURN_KEY];
     }
+}
 
-    pub fn sub(&self) -> u16 {
-        (self.val >> 48) as u16
-    }
+/// Mate scores are relative to the root, so they are stored relative to the entry's own position
+pub fn value_to_tt(score: i32, ply: usize) -> i32 {
+    if score >= MATE_BOUND { score + ply as i32 }
+    else if score <= -MATE_BOUND { score - ply as i32 }
+    else { score }
+}
+
+/// Convert a stored score back so that any mate is relative to the root
+pub fn value_from_tt(score: i32, ply: usize) -> i32 {
+    if score >= MATE_BOUND { score - ply as i32 }
+    else if score <= -MATE_BOUND { score + ply as i32 }
+    else { score }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Bound { Exact = 0, Lower = 1, Upper = 2 }
 
+impl Bound {
+    fn from_bits(bits: u64) -> Self {
+        match bits {
+            1 => Bound::Lower,
+            2 => Bound::Upper,
+            _ => Bound::Exact
+        }
+    }
+}
+
+// Layout of a packed entry, from the lowest bit:
+// score (32 bits), best move (20), depth (8), bound (2), occupied (1)
+const MOVE_SHIFT: u64 = 32;
+const DEPTH_SHIFT: u64 = 52;
+const BOUND_SHIFT: u64 = 60;
+const OCCUPIED: u64 = 1 << 62;
+
+/// The lowest bits of a stored key hold the generation of the search which wrote it, in place
+/// of key bits that are already implied by the index of the cluster
+const GENERATION_MASK: u64 = 0xFF;
+
+pub const CLUSTER_SIZE: usize = 4;
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct Entry {
     pub score: i32,
     pub best_move: Move,
-    pub hash16: u16,
     pub depth: u8,
-    pub info: u8 // Upper 2 bits -> bound, lowest bit -> ancient
+    pub bound: Bound
 }
 
 impl Entry {
-    const NULL: Entry = Entry { score: 0, best_move: Move::NULL, hash16: 0, depth: 0, info: 0 };
+    pub fn pack(&self) -> u64 {
+        (self.score as u32 as u64)
+        | (self.best_move.bits() as u64) << MOVE_SHIFT
+        | (self.depth as u64) << DEPTH_SHIFT
+        | (self.bound as u64) << BOUND_SHIFT
+        | OCCUPIED
+    }
+
+    pub fn unpack(data: u64) -> Self {
+        Entry {
+            score: data as u32 as i32,
+            best_move: Move::from_bits((data >> MOVE_SHIFT) as u32 & 0xFFFFF),
+            depth: (data >> DEPTH_SHIFT) as u8,
+            bound: Bound::from_bits((data >> BOUND_SHIFT) & 0b11)
+        }
+    }
+}
+
+/// A table slot which may be read and written by many threads at once without locking.
+/// The key is stored xor'd with the data, so that a torn write, where the key and data
+/// come from different entries, fails verification instead of returning a corrupt entry.
+/// This relies on usize being 64 bits wide
+pub struct Slot {
+    key: AtomicUsize,
+    data: AtomicUsize
+}
+
+impl Slot {
+    fn new() -> Self {
+        Slot { key: AtomicUsize::new(0), data: AtomicUsize::new(0) }
+    }
+
+    /// Return the raw data and the generation stored for this hash, if there are any
+    fn load(&self, hash: Hash) -> Option<(u64, u8)> {
+        let data = self.data.load(Ordering::Relaxed) as u64;
+        let key = self.key.load(Ordering::Relaxed) as u64;
+
+        if data != 0 && (key ^ data) & !GENERATION_MASK == hash.val & !GENERATION_MASK {
+            Some((data, (key & GENERATION_MASK) as u8))
+        } else {
+            None
+        }
+    }
+
+    fn store(&self, hash: Hash, data: u64, generation: u8) {
+        let key = ((hash.val ^ data) & !GENERATION_MASK) | generation as u64;
+        self.key.store(key as usize, Ordering::Relaxed);
+        self.data.store(data as usize, Ordering::Relaxed);
+    }
+
+    /// Mark an entry as belonging to the current search, without changing its contents
+    fn refresh(&self, generation: u8) {
+        let key = self.key.load(Ordering::Relaxed) as u64;
+        self.key.store(((key & !GENERATION_MASK) | generation as u64) as usize, Ordering::Relaxed);
+    }
 
-    pub fn is_empty(&self) -> bool {
-        *self == Entry::NULL
+    fn generation(&self) -> u8 {
+        (self.key.load(Ordering::Relaxed) as u64 & GENERATION_MASK) as u8
     }
 
-    pub fn ancient(&self) -> bool {
-        self.info & 0b1 != 0
+    fn depth(&self) -> u8 {
+        (self.data.load(Ordering::Relaxed) as u64 >> DEPTH_SHIFT) as u8
     }
 
-    pub fn bound(&self) -> Bound {
-        unsafe { mem::transmute(self.info >> 6) }
+    fn is_empty(&self) -> bool {
+        self.data.load(Ordering::Relaxed) == 0
     }
+}
 
-    pub fn compare(&self, hash: &Hash) -> bool {
-        self.hash16 == hash.sub()
+/// Entries which share an index, filling a cache line
+#[repr(align(64))]
+pub struct Cluster {
+    slots: [Slot; CLUSTER_SIZE]
+}
+
+impl Cluster {
+    fn new() -> Self {
+        Cluster { slots: [Slot::new(), Slot::new(), Slot::new(), Slot::new()] }
     }
 }
 
+/// The transposition table, which is shared by every search thread.
+/// The number of clusters is a power of two, so that the index is the low bits of the hash
 pub struct Table {
-    pub entries: Vec<Entry>
+    clusters: Vec<Cluster>,
+    generation: AtomicUsize
 }
 
 impl Table {
+    /// Create a table holding the largest power of two number of entries which fits in `size`,
+    /// so that it never takes more memory than it is given
     pub fn empty(size: usize) -> Self {
-        Table { entries: vec![Entry::NULL; size] }
+        let mut num_clusters = 1;
+        while num_clusters * 2 * CLUSTER_SIZE <= size {
+            num_clusters *= 2;
+        }
+
+        Table {
+            clusters: (0..num_clusters).map(|_| Cluster::new()).collect(),
+            generation: AtomicUsize::new(0)
+        }
     }
 
     pub fn empty_mb(size_mb: usize) -> Self {
-        Table::empty(size_mb * 1024 * 1024 / mem::size_of::<Entry>())
+        Table::empty(size_mb * 1024 * 1024 / mem::size_of::<Slot>())
     }
 
-    pub fn probe(&self, hash: Hash, depth: u8, alpha: i32, beta: i32) -> (Option<i32>, Move) {
-        let entry = &self.entries[hash.val as usize % self.size()];
+    fn cluster(&self, hash: Hash) -> &Cluster {
+        &self.clusters[hash.val as usize & (self.clusters.len() - 1)]
+    }
+
+    fn generation(&self) -> u8 {
+        self.generation.load(Ordering::Relaxed) as u8
+    }
+
+    /// Start a new search, so that entries from previous searches are replaced first
+    pub fn new_search(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn entry(&self, hash: Hash) -> Option<Entry> {
+        let generation = self.generation();
+
+        for slot in &self.cluster(hash).slots {
+            if let Some((data, entry_generation)) = slot.load(hash) {
+                if entry_generation != generation { slot.refresh(generation) }
+                return Some(Entry::unpack(data))
+            }
+        }
+        None
+    }
+
+    pub fn probe(&self, hash: Hash, depth: u8, alpha: i32, beta: i32, ply: usize) -> (Option<i32>, Move) {
+        if let Some(entry) = self.entry(hash) {
+            let score = value_from_tt(entry.score, ply);
 
-        if !entry.is_empty() && entry.compare(&hash) {
-            // if entry.depth() == depth {
-            //     println!("{} {:?} d = {} a = {} b = {}", entry.score, entry.bound(), depth, alpha, beta);
-            // }
             if  entry.depth >= depth &&
-                match entry.bound() {
-                    Bound::Lower => entry.score >= beta,
-                    Bound::Upper => entry.score <= alpha,
+                match entry.bound {
+                    Bound::Lower => score >= beta,
+                    Bound::Upper => score <= alpha,
                     Bound::Exact => true }
-                { return (Some(entry.score), Move::NULL) }
+                { return (Some(score), Move::NULL) }
 
             return (None, entry.best_move)
         }
@@ -135,23 +260,44 @@ impl Table {
     }
 
     pub fn best_move(&self, hash: Hash) -> Option<Move> {
-        let entry = &self.entries[hash.val as usize % self.size()];
-
-        if !entry.is_empty() && entry.compare(&hash) && entry.best_move != Move::NULL {
-            return Some(entry.best_move)
-        }
-        None
+        self.entry(hash).map(|entry| entry.best_move).and_then(|mv|
+            if mv != Move::NULL { Some(mv) } else { None })
     }
 
-    pub fn record(&mut self, board: &Board, score: i32, best_move: Move, depth: u8, bound: Bound) {
-        let size = self.size();
-        let entry = &mut self.entries[board.hash.val as usize % size];
+    /// Store an entry, overwriting any previous entry for the same position unless that was
+    /// searched deeper and the new score isn't exact. Otherwise the entry with the lowest depth
+    /// is replaced, where older generations count as shallower
+    pub fn record(&self, board: &Board, score: i32, best_move: Move, depth: u8, bound: Bound, ply: usize) {
+        let hash = board.hash;
+        let generation = self.generation();
+        let cluster = self.cluster(hash);
+
+        let mut replace = &cluster.slots[0];
+        let mut best_move = best_move;
+
+        for slot in &cluster.slots {
+            if let Some((data, _)) = slot.load(hash) {
+                let old = Entry::unpack(data);
+                if bound != Bound::Exact && depth.saturating_add(2) < old.depth { return }
+
+                // Keep the best move from a previous search, unless there is a new one
+                if best_move == Move::NULL { best_move = old.best_move }
+                replace = slot;
+                break
+            }
+
+            if slot.is_empty() {
+                replace = slot;
+                break
+            }
 
-        if entry.is_empty() || entry.depth <= depth || entry.ancient() {
-            let info = (bound as u8) << 6;
-            *entry = Entry { score: score, best_move: best_move, hash16: board.hash.sub(),
-                depth: depth, info: info };
+            if replace_value(slot, generation) < replace_value(replace, generation) {
+                replace = slot;
+            }
         }
+
+        let entry = Entry { score: value_to_tt(score, ply), best_move: best_move, depth: depth, bound: bound };
+        replace.store(hash, entry.pack(), generation);
     }
 
     pub fn pv(&self, board: &Board) -> Vec<Move> {
@@ -162,7 +308,8 @@ impl Table {
     }
 
     pub fn pv_cycle_track(&self, mut board: Board, pv: &mut Vec<Move>, visited: &mut HashSet<Hash>) {
-        let mv = self.best_move(board.hash);
+        // A colliding entry could hold a move which is illegal here
+        let mv = self.best_move(board.hash).and_then(|mv| if board.is_legal(mv) { Some(mv) } else { None });
 
         if let Some(m) = mv {
             pv.push(m);
@@ -174,18 +321,26 @@ impl Table {
         }
     }
 
+    /// The total number of entries
     pub fn size(&self) -> usize {
-        self.entries.len()
+        self.clusters.len() * CLUSTER_SIZE
     }
 
-    pub fn set_ancient(&mut self) -> usize {
-        let mut num = 0;
-        for entry in &mut self.entries {
-            if !entry.is_empty() {
-                num += 1;
-                entry.info |= 0b1;
-            }
-        }
-        num
+    /// An estimate of how full the table is, in permill, from the entries of this search
+    pub fn hashfull(&self) -> usize {
+        let generation = self.generation();
+        let sample = &self.clusters[..min(1000, self.clusters.len())];
+
+        let used = sample.iter().flat_map(|cluster| cluster.slots.iter())
+                         .filter(|slot| !slot.is_empty() && slot.generation() == generation)
+                         .count();
+
+        used * 1000 / (sample.len() * CLUSTER_SIZE)
     }
 }
+
+/// Entries are kept by depth, with each generation of age counting as several plies
+fn replace_value(slot: &Slot, generation: u8) -> i32 {
+    let age = generation.wrapping_sub(slot.generation()) as i32;
+    slot.depth() as i32 - 8 * age
+}
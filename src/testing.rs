@@ -1,8 +1,344 @@
 #![cfg(test)]
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::fs::File;
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 use test::Bencher;
 use magics::*;
-use board::Board;
+use board::{Board, GameState, START_FEN};
+use epd::Epd;
+use perft;
+use pgn::Game;
+use print::{score_str, mate_in};
+use _move::Move;
+use search::{Searcher, INFINITY, VALUE_MATE, MAX_DEPTH, TB_WIN};
+use syzygy::*;
+use table::{Table, Slot, Bound, CLUSTER_SIZE, value_to_tt, value_from_tt};
+use timer::Timer;
+use uci::EngineSettings;
 use uci;
+use util::ParseError;
+
+const KIWIPETE: &'static str = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1";
+
+/// The depth the perft suite is checked to by `cargo test`, which is every count up to a few million
+const PERFT_DEPTH: u8 = 4;
+
+/// A searcher with the default settings, set up by the arguments of a `position` command
+fn searcher(position: &str) -> Searcher {
+    let mut searcher = Searcher::new(EngineSettings::default(), Timer::default(Arc::new(AtomicBool::new(false))));
+    searcher.position(&mut position.split_whitespace()).unwrap();
+    searcher
+}
+
+#[test]
+pub fn perft_suite() {
+    uci::init();
+
+    let file = BufReader::new(File::open("testing/positions/perftsuite.epd").unwrap());
+
+    for line in file.lines() {
+        let line = line.unwrap();
+        let epd = Epd::parse(&line).unwrap();
+
+        if let Some((depth, expected, found)) = epd.check_perft(PERFT_DEPTH) {
+            panic!("{}\nPerft {} expected {} but found {}", line, depth, expected, found);
+        }
+    }
+}
+
+#[test]
+pub fn fen_round_trip() {
+    uci::init();
+
+    let file = BufReader::new(File::open("testing/positions/perftsuite.epd").unwrap());
+    let suite = file.lines().map(|line| line.unwrap().split(';').next().unwrap().trim().to_string());
+    let others = ["r3k2r/8/8/8/8/8/8/R3K2R w - - 57 123",
+                  "rnbqkbnr/ppp1pppp/8/8/3pP3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 3"];
+
+    for fen in suite.chain(others.iter().map(|fen| fen.to_string())) {
+        let board = Board::from_fen(&mut fen.split_whitespace()).unwrap();
+        assert_eq!(board.to_fen(), fen);
+    }
+}
+
+#[test]
+pub fn malformed_input() {
+    uci::init();
+
+    let fen = |fen: &str| Board::from_fen(&mut fen.split_whitespace()).err();
+    let invalid_board = |board: &str| Some(ParseError::InvalidBoard(board.into()));
+
+    assert_eq!(fen("rnbqkbnr/ppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"),
+               invalid_board("rnbqkbnr/ppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR"));
+    assert_eq!(fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNRR w KQkq - 0 1"),
+               invalid_board("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNRR"));
+    assert_eq!(fen("4k3/8/8/8/8/8/8/3KK3 w - - 0 1"), invalid_board("4k3/8/8/8/8/8/8/3KK3"));
+    assert_eq!(fen("4k3/8/8/8/8/8/8/4K3 w - e4 0 1"), Some(ParseError::InvalidEnPassant("e4".into())));
+    assert_eq!(fen("4k3/8/8/8/8/8/8/4K3 w - - x 1"), Some(ParseError::InvalidValue("x".into())));
+    assert_eq!(fen("4k3/8/8/8/8/8/8/4K3 w - - 0 y"), Some(ParseError::InvalidValue("y".into())));
+    assert_eq!(fen("4k3/8/8/8/8/8/8/4K3 w -"), Some(ParseError::Missing("en passant square")));
+
+    let mut timer = Timer::default(Arc::new(AtomicBool::new(false)));
+    assert_eq!(timer.replace(&mut "wtime abc btime 1000".split_whitespace()).err(),
+               Some(ParseError::InvalidValue("abc".into())));
+
+    // The root is left as it was when any move is wrong
+    let mut searcher = searcher("startpos moves e2e4");
+    let root = searcher.root.to_fen();
+    assert_eq!(searcher.position(&mut "startpos moves e2e4 e2e4".split_whitespace()).err(),
+               Some(ParseError::IllegalMove("e2e4".into())));
+    assert_eq!(searcher.position(&mut "startpos moves e2e4 e7e9".split_whitespace()).err(),
+               Some(ParseError::InvalidMove("e7e9".into())));
+    assert_eq!(searcher.root.to_fen(), root);
+}
+
+#[test]
+pub fn san_round_trip() {
+    uci::init();
+
+    let file = BufReader::new(File::open("testing/positions/perftsuite.epd").unwrap());
+
+    for line in file.lines() {
+        let board = Epd::parse(&line.unwrap()).unwrap().board;
+
+        for mv in board.legal_moves() {
+            let san = board.to_san(mv);
+            assert!(board.parse_san(&san).ok() == Some(mv), "{} {} {}", board.to_fen(), mv, san);
+        }
+    }
+
+    // Other ways of writing a move, with the SAN they are read as
+    for &(fen, sloppy, san) in &[("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "0-0", "O-O"),
+                                 ("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "e1g1", "O-O"),
+                                 ("4k3/1P6/8/8/8/8/8/4K3 w - - 0 1", "b8q", "b8=Q+"),
+                                 ("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+                                  "Ne5xf7", "Nxf7"),
+                                 ("4k3/8/8/8/8/8/8/R3K3 w - - 0 1", "Ra8", "Ra8+")] {
+        let board = Board::from_fen(&mut fen.split_whitespace()).unwrap();
+        assert_eq!(board.parse_san(sloppy).map(|mv| board.to_san(mv)).ok(), Some(san.to_string()));
+    }
+}
+
+/// The rows of the tables at https://www.chessprogramming.org/Perft_Results, which count a check
+/// as discovered only when the piece which moved doesn't give it as well
+#[test]
+pub fn perft_stats() {
+    uci::init();
+
+    for &(fen, depth, expected) in &[(START_FEN, 3, [8902, 34, 0, 0, 0, 12, 0, 0, 0]),
+                                     (KIWIPETE, 3, [97862, 17102, 45, 3162, 0, 993, 0, 0, 1]),
+                                     ("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1", 4,
+                                      [43238, 3348, 123, 0, 0, 1680, 106, 0, 17])] {
+        let board = Board::from_fen(&mut fen.split_whitespace()).unwrap();
+        let s = perft::stats(&board, depth);
+        assert_eq!([s.nodes, s.captures, s.en_passants, s.castles, s.promotions,
+                    s.checks, s.discovered_checks, s.double_checks, s.checkmates], expected, "{}", fen);
+    }
+}
+
+#[test]
+pub fn perft_threads_and_hash() {
+    uci::init();
+
+    let board = Board::from_fen(&mut KIWIPETE.split_whitespace()).unwrap();
+    let plain = perft::perft(&board, 4, None);
+    let table = Arc::new(perft::PerftTable::new(16));
+    let divided: usize = perft::divide(&board, 4, 4, Some(table)).iter().map(|&(_, count)| count).sum();
+
+    assert_eq!((plain, divided), (4085603, 4085603));
+}
+
+#[test]
+pub fn pgn_round_trip() {
+    uci::init();
+
+    let text = "[Event \"Round trip\"]\n[Date \"2017.05.01\"]\n[Result \"1-0\"]\n[Annotator \"A \\\"quoted\\\" name\"]\n\
+                [SetUp \"1\"]\n[FEN \"r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3\"]\n\n\
+                {From a known position} 3. Bb5 $1 {The Spanish} a6 (3... Nf6 4. O-O (4. d3 {Quiet}) Nxe4 $6)\n\
+                (3... f5!?) 4. Ba4 Nf6 5. O-O?! 1-0";
+    let game = Game::parse(text).unwrap();
+    let written = game.to_string();
+    let read = Game::parse(&written).unwrap();
+
+    assert_eq!(read.to_string(), written);
+    assert_eq!(read.tag("Annotator"), Some("A \"quoted\" name"));
+    assert_eq!(read.start.to_fen(), game.start.to_fen());
+    assert_eq!(read.result, "1-0");
+    assert_eq!(read.moves.len(), 5);
+    assert_eq!(read.moves[0].leading, ["From a known position"]);
+    assert_eq!(read.moves[0].nags, [1]);
+    assert_eq!(read.moves[0].comments, ["The Spanish"]);
+    assert_eq!(read.moves[1].variations.len(), 2);
+    assert_eq!(read.moves[1].variations[0][1].variations[0][0].comments, ["Quiet"]);
+    assert_eq!(read.moves[1].variations[0][2].nags, [6]);
+    assert_eq!(read.moves[1].variations[1][0].nags, [5]);
+    assert_eq!(read.moves[4].nags, [6]);
+
+    let game = Game::parse("1. e4 e5 2. Nf3 (2. {c} Nc3) Nc6 *").unwrap();
+    let read = Game::parse(&game.to_string()).unwrap();
+    assert_eq!(read.moves[2].variations[0][0].leading, ["c"]);
+
+    // A brace can't be written inside a comment
+    let mut game = Game::new(Board::start_position());
+    game.push(Board::start_position().parse_san("e4").unwrap());
+    game.moves[0].comments.push("Closed} early".into());
+    assert_eq!(Game::parse(&game.to_string()).unwrap().moves[0].comments, ["Closed early"]);
+}
+
+#[test]
+pub fn go_depth_beyond_max() {
+    uci::init();
+
+    let mut searcher = searcher("fen 6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1");
+    searcher.timer.replace(&mut "depth 500".split_whitespace()).unwrap();
+    assert_eq!(searcher.think().to_string(), "a1a8");
+    assert!(!searcher.timer.should_search(MAX_DEPTH + 1));
+}
+
+#[test]
+pub fn game_over() {
+    uci::init();
+
+    for &(position, state) in &[("startpos moves g1f3 g8f6 f3g1 f6g8 g1f3 g8f6 f3g1", GameState::Ongoing),
+                                ("startpos moves g1f3 g8f6 f3g1 f6g8 g1f3 g8f6 f3g1 f6g8", GameState::Repetition),
+                                ("fen 7k/7Q/6K1/8/8/8/8/8 b - - 100 80", GameState::Checkmate),
+                                ("fen 7k/8/6K1/8/8/8/8/Q7 b - - 100 80", GameState::FiftyMoves),
+                                ("fen 7k/8/6K1/8/8/8/8/Q7 b - - 99 80", GameState::Ongoing),
+                                ("fen 7k/5Q2/6K1/8/8/8/8/8 b - - 0 1", GameState::Stalemate)] {
+        let searcher = searcher(position);
+        assert_eq!(searcher.game_state(), state, "{}", position);
+
+        // The search only scores repetitions and the fifty move rule as draws
+        let is_draw = state == GameState::Repetition || state == GameState::FiftyMoves;
+        assert_eq!(searcher.is_draw(&searcher.root), is_draw, "{}", position);
+    }
+
+    // Mating on the hundredth ply wins, rather than being a draw
+    let mut searcher = searcher("fen 7k/5K2/6Q1/8/8/8/8/8 w - - 99 120");
+    searcher.timer.replace(&mut "depth 4".split_whitespace()).unwrap();
+    let mut board = searcher.root;
+    board.make_move(searcher.think());
+    assert_eq!(board.game_state(&[]), GameState::Checkmate);
+}
+
+#[test]
+pub fn mate_scores() {
+    uci::init();
+
+    // Mating on the next ply is mate in 1, while being mated after our move and the reply is mate in -1
+    assert_eq!(score_str(VALUE_MATE - 1), "mate 1");
+    assert_eq!(score_str(-VALUE_MATE + 2), "mate -1");
+    assert_eq!((mate_in(VALUE_MATE - 3), mate_in(-VALUE_MATE + 4)), (Some(2), Some(-2)));
+    assert_eq!(score_str(350), "cp 35");
+    assert_eq!(score_str(-1234), "cp -123");
+    assert_eq!((mate_in(TB_WIN), mate_in(-TB_WIN)), (None, None));
+
+    // A mate stored three plies from the root is as far from the position when read back at five
+    let board = Board::start_position();
+    let table = Table::empty_mb(1);
+    for &(score, expected) in &[(VALUE_MATE - 4, VALUE_MATE - 6),
+                                (-VALUE_MATE + 5, -VALUE_MATE + 7),
+                                (1234, 1234)] {
+        assert_eq!(value_from_tt(value_to_tt(score, 3), 5), expected);
+
+        table.record(&board, score, Move::NULL, 5, Bound::Exact, 3);
+        assert_eq!(table.probe(board.hash, 5, -INFINITY, INFINITY, 5).0, Some(expected));
+    }
+}
+
+#[test]
+pub fn table_replacement() {
+    uci::init();
+
+    // The size in megabytes is a limit, rather than a target
+    let size = Table::empty_mb(100).size() * mem::size_of::<Slot>();
+    assert!(size <= 100 << 20 && size > 50 << 20, "{}", size);
+
+    // Every position shares the only cluster
+    let table = Table::empty(CLUSTER_SIZE);
+    let start = Board::start_position();
+    let boards: Vec<Board> = ["e4", "d4", "Nf3", "c4", "Nc3", "e3"].iter().map(|san| {
+        let mut board = start;
+        board.make_move(start.parse_san(san).unwrap());
+        board
+    }).collect();
+    let depth = |board: &Board| table.entry(board.hash).map(|entry| entry.depth);
+
+    table.new_search();
+    for (board, depth) in boards.iter().zip(&[9, 2, 3, 4]) {
+        table.record(board, 0, Move::NULL, *depth, Bound::Exact, 0);
+    }
+    assert_eq!(table.hashfull(), 1000);
+
+    // The shallowest entry is replaced, and a shallower bound for the same position is ignored
+    table.record(&boards[4], 0, Move::NULL, 5, Bound::Exact, 0);
+    table.record(&boards[4], 0, Move::NULL, 1, Bound::Upper, 0);
+    let depths: Vec<_> = boards[..5].iter().map(&depth).collect();
+    assert_eq!(depths, [Some(9), None, Some(3), Some(4), Some(5)]);
+
+    // Entries from the previous search count as shallower, unless they have been probed since
+    table.new_search();
+    assert_eq!(table.hashfull(), 0);
+    for board in &boards[2..5] { depth(board); }
+    assert_eq!(table.hashfull(), 750);
+
+    table.record(&boards[5], 0, Move::NULL, 1, Bound::Exact, 0);
+    assert_eq!((depth(&boards[0]), depth(&boards[5])), (None, Some(1)));
+}
+
+/// The keys of the Polyglot specification's examples
+#[test]
+pub fn book_keys() {
+    uci::init();
+
+    let mut board = Board::start_position();
+    assert_eq!(board.book_key(), 0x463b96181691fc9c);
+
+    let mv = board.parse_san("e4").unwrap();
+    board.make_move(mv);
+    assert_eq!(board.book_key(), 0x823c9b50fd114196);
+}
+
+/// The tables in testing/syzygy are generated in the Syzygy format by retrograde analysis
+#[test]
+pub fn syzygy_probe() {
+    uci::init();
+
+    let tablebases = Tablebases::new("testing/syzygy");
+    assert_eq!((tablebases.len(), tablebases.max_pieces), (2, 3));
+
+    let board = |fen: &str| Board::from_fen(&mut fen.split_whitespace()).unwrap();
+
+    for &(fen, wdl) in &[("8/8/8/4k3/8/8/8/R3K3 w - - 0 1", WDL_WIN),
+                         ("8/8/8/4K3/8/8/8/r3k3 b - - 0 1", WDL_WIN),
+                         ("8/8/8/8/8/8/2k5/1R5K b - - 0 1", WDL_DRAW),
+                         ("k6R/8/1K6/8/8/8/8/8 b - - 0 1", WDL_LOSS),
+                         ("8/8/8/8/8/4k3/4P3/4K3 w - - 0 1", WDL_DRAW),
+                         ("4k3/8/4K3/4P3/8/8/8/8 w - - 0 1", WDL_WIN),
+                         ("8/8/8/8/4p3/4k3/8/4K3 b - - 0 1", WDL_WIN),
+                         ("8/8/8/8/8/8/P7/K6k b - - 0 1", WDL_LOSS)] {
+        assert_eq!(tablebases.probe_wdl(&board(fen)), Some(wdl), "{}", fen);
+    }
+
+    for &(fen, dtz) in &[("k7/8/1K6/8/8/8/8/7R w - - 0 1", 1),
+                         ("k6R/8/1K6/8/8/8/8/8 b - - 0 1", -1),
+                         ("8/8/8/8/8/8/P7/K6k w - - 0 1", 1),
+                         ("8/8/8/8/8/4k3/4P3/4K3 w - - 0 1", 0)] {
+        assert_eq!(tablebases.probe_dtz(&board(fen)), Some(dtz), "{}", fen);
+    }
+
+    // Leaving the rook to be taken throws away the win
+    let root = board("8/8/8/8/8/8/2k5/1R5K w - - 0 1");
+    let moves = root.legal_moves();
+    let ranks = tablebases.rank_root_moves(&root, &moves).unwrap();
+    for (mv, rank) in moves.iter().zip(ranks) {
+        let expected = match &*mv.to_string() { "h1g1" => 0, "b1b8" => 1000, _ => continue };
+        assert_eq!(rank, expected, "{}", mv);
+    }
+}
 
 #[bench]
 pub fn a_move_gen(b: &mut Bencher) {
@@ -1,31 +1,70 @@
+use std::cmp::min;
 use std::sync::atomic::Ordering;
 use time;
 use types::*;
 use util::*;
+use search::MAX_DEPTH;
+
+/// The timer is polled whenever the node count is a multiple of this interval
+const POLL_MASK: usize = 1024 - 1;
+
+/// The most the time we would like to spend on a move may grow by, after the score has dropped
+/// at the root. The hard deadline stays the same
+const MAX_EXTENSION: f64 = 2.0;
+
+const GO_OPTIONS: [&'static str; 12] = ["wtime", "btime", "winc", "binc", "movestogo", "ponder",
+                                        "infinite", "depth", "nodes", "movetime", "mate", "searchmoves"];
 
 pub struct TimeSettings {
     times_for: [f64; 2],
     inc_for: [f64; 2],
     moves_to_go: usize,
     ponder: bool,
-    infinite: bool
+    infinite: bool,
+    has_clock: bool,
+    depth: Option<usize>,
+    nodes: Option<usize>,
+    move_time: Option<f64>,
+    mate: Option<usize>,
+    search_moves: Vec<String>
 }
 
 impl TimeSettings {
-    pub fn parse(mut self, params: &mut Params) -> Self {
+    pub fn parse(mut self, params: &mut Params) -> Result<Self, ParseError> {
+        let mut params = params.peekable();
+
         while let Some(option) = params.next() {
             match option {
-                "wtime" => self.times_for[I_WHITE] = parse(params.next()),
-                "btime" => self.times_for[I_BLACK] = parse(params.next()),
-                "winc"  => self.inc_for[I_WHITE]   = parse(params.next()),
-                "binc"  => self.inc_for[I_BLACK]   = parse(params.next()),
-                "movestogo" => self.moves_to_go    = parse(params.next()),
+                "wtime" => self.times_for[I_WHITE] = try!(parse(params.next())),
+                "btime" => self.times_for[I_BLACK] = try!(parse(params.next())),
+                "winc"  => self.inc_for[I_WHITE]   = try!(parse(params.next())),
+                "binc"  => self.inc_for[I_BLACK]   = try!(parse(params.next())),
+                "movestogo" => self.moves_to_go    = try!(parse(params.next())),
                 "ponder"   => self.ponder = true,
                 "infinite" => self.infinite = true,
+                "depth"    => self.depth = Some(min(try!(parse(params.next())), MAX_DEPTH)),
+                "nodes"    => self.nodes = Some(try!(parse(params.next()))),
+                "movetime" => self.move_time = Some(try!(parse(params.next()))),
+                "mate"     => self.mate = Some(try!(parse(params.next()))),
+                "searchmoves" => {
+                    // Every word up to the next option is a move
+                    while params.peek().map_or(false, |word| !GO_OPTIONS.contains(word)) {
+                        self.search_moves.push(params.next().unwrap().into());
+                    }
+                },
                 _ => ()
             }
+            if option == "wtime" || option == "btime" { self.has_clock = true }
         }
-        self
+        Ok(self)
+    }
+
+    /// Only the clock, and any explicit move time, are limits on time. Without any limits
+    /// at all, we fall back to the default clock rather than searching forever
+    pub fn is_timed(&self) -> bool {
+        !self.infinite && (
+        self.has_clock || self.move_time.is_some() ||
+        self.depth.is_none() && self.nodes.is_none() && self.mate.is_none())
     }
 
     pub fn time(&self, side: usize) -> f64 {
@@ -44,7 +83,13 @@ impl Default for TimeSettings {
             inc_for: [0.0, 0.0],
             moves_to_go: 40,
             ponder: false,
-            infinite: false
+            infinite: false,
+            has_clock: false,
+            depth: None,
+            nodes: None,
+            move_time: None,
+            mate: None,
+            search_moves: Vec::new()
         }
     }
 }
@@ -56,6 +101,9 @@ pub struct Timer {
     times: Vec<f64>,
     side: usize,
     safety: f64,
+    extension: f64,
+    /// The score before it dropped, which it has to recover to for the extension to end
+    dropped_from: Option<i32>,
     init: f64
 }
 
@@ -68,6 +116,8 @@ impl Timer {
             times: vec![0.0],
             side: !(I_WHITE | I_BLACK), // Initialize later
             safety: 0.1,
+            extension: 1.0,
+            dropped_from: None,
             init: 0.0
         }
     }
@@ -76,36 +126,108 @@ impl Timer {
         Timer::new(flag, TimeSettings::default())
     }
 
-    pub fn replace(&mut self, params: &mut Params) {
-        let settings = TimeSettings::default().parse(params);
+    /// A timer which only stops when the flag is set
+    pub fn infinite(flag: Flag) -> Self {
+        Timer::new(flag, TimeSettings { infinite: true, .. TimeSettings::default() })
+    }
+
+    /// Replace the timer with new settings, clearing any previous stop request.
+    /// This happens before the search thread is started, so that a `stop` can't be lost
+    pub fn replace(&mut self, params: &mut Params) -> Result<(), ParseError> {
+        let settings = try!(TimeSettings::default().parse(params));
         *self = Timer::new(self.should_stop.clone(), settings);
+        self.should_stop.store(false, Ordering::SeqCst);
+        Ok(())
     }
 
     pub fn start(&mut self, side: u8) {
         self.init = time::precise_time_s();
         self.side = side as usize;
-        self.should_stop.store(false, Ordering::Relaxed);
     }
 
-    pub fn toc(&mut self, node_count: usize) {
+    /// Record an iteration which has completed with the given score
+    pub fn toc(&mut self, node_count: usize, score: i32) {
         self.nodes.push(node_count);
         let dt = self.elapsed();
         self.times.push(dt);
+
+        if self.dropped_from.map_or(false, |dropped_from| score >= dropped_from) {
+            self.extension = 1.0;
+            self.dropped_from = None;
+        }
     }
 
     pub fn elapsed(&self) -> f64 {
         time::precise_time_s() - self.init
     }
 
+    /// The root moves the search is restricted to, as given by `go searchmoves`
+    pub fn search_moves(&self) -> &[String] {
+        &self.settings.search_moves
+    }
+
+    pub fn is_timed(&self) -> bool {
+        self.settings.is_timed()
+    }
+
+    /// The number of moves within which `go mate` asks us to find a mate
+    pub fn mate(&self) -> Option<usize> {
+        self.settings.mate
+    }
+
+    /// The time we would like to spend on this move
+    pub fn alloc_time(&self) -> f64 {
+        if let Some(move_time) = self.settings.move_time {
+            return move_time / 1000.0
+        }
+
+        (1.0 - self.safety) * self.settings.time(self.side) / self.settings.moves_to_go as f64
+        + self.settings.inc(self.side)
+    }
+
+    /// The score of the best move has dropped below the aspiration window from its score at the
+    /// previous depth, so keep starting iterations for longer, until it recovers
+    pub fn fail_low(&mut self, prev_score: i32) {
+        self.extension = (self.extension + 0.5).min(MAX_EXTENSION);
+        if self.dropped_from.is_none() { self.dropped_from = Some(prev_score) }
+    }
+
+    /// The hard deadline, after which a search in progress is abandoned
+    pub fn max_time(&self) -> f64 {
+        if self.settings.move_time.is_some() { return self.alloc_time() }
+
+        let remaining = (1.0 - self.safety) * self.settings.time(self.side);
+        (self.alloc_time() * 3.0).min(remaining)
+    }
+
+    fn node_limit_reached(&self, node_count: usize) -> bool {
+        self.settings.nodes.map_or(false, |nodes| node_count >= nodes)
+    }
+
+    /// Return whether the search in progress must be aborted, regardless of its depth. The node
+    /// limit is on the nodes of every thread, while the clock and stop flag are only polled
+    /// periodically, by the nodes of this thread
+    pub fn should_abort(&self, node_count: usize, total_nodes: usize) -> bool {
+        self.node_limit_reached(total_nodes) ||
+        node_count & POLL_MASK == 0 && (
+        self.should_stop.load(Ordering::Relaxed) ||
+        self.settings.is_timed() && self.elapsed() > self.max_time())
+    }
+
     /// Return whether we should search to a given depth, or give the best move so far
     pub fn should_search(&self, depth: usize) -> bool {
+        if depth > self.settings.depth.unwrap_or(MAX_DEPTH) { return false }
         if depth <= 2 { return true }
+        if self.node_limit_reached(self.nodes[depth-1]) { return false }
+        if self.settings.move_time.is_some() {
+            return !self.should_stop.load(Ordering::Relaxed) && self.elapsed() < self.alloc_time()
+        }
+
         let estimate = self.times[depth-1] * self.nodes[depth-1] as f64 / self.nodes[depth-2] as f64;
-        let alloc_time = (1.0 - self.safety) * self.settings.time(self.side) / self.settings.moves_to_go as f64
-                         + self.settings.inc(self.side);
+        let alloc_time = self.extension * self.alloc_time();
 
         !self.should_stop.load(Ordering::Relaxed) && (
-        self.settings.infinite ||
+        !self.settings.is_timed() ||
         alloc_time - self.times[depth-1] > estimate * 0.3 ||
         alloc_time / 1.5 > self.elapsed())
     }
@@ -1,27 +1,26 @@
 use std::io::prelude::*;
 use std::io::{stdin, BufReader};
+use std::cmp::max;
 use std::fs::File;
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
+use std::sync::{Arc, Once, ONCE_INIT};
 use time;
 
 use types::*;
 use util::*;
 use board::Board;
+use engine::Engine;
+use epd::{self, Epd};
 use magics;
+use perft::{self, PerftTable};
 use table;
 use search::Searcher;
-use timer::Timer;
 
 const ENGINE_NAME: &'static str = "Crabby 2.0.0";
 
 pub fn main_loop() {
     init();
 
-    let should_stop = Arc::new(AtomicBool::new(false));
-    let timer = Timer::default(should_stop.clone());
-    let searcher = Arc::new(Mutex::new(Searcher::new(EngineSettings::default(), timer)));
+    let mut engine = Engine::new();
 
     let stdin = stdin();
     for line in stdin.lock().lines() {
@@ -30,87 +29,180 @@ pub fn main_loop() {
 
         if let Some(first_word) = params.next() {
             match first_word {
-                "uci"        => uci(),
-                "isready"    => println!("readyok"),
-                "setoption"  => lock!(searcher).update_settings(&mut params),
-                "ucinewgame" => lock!(searcher).reset(),
-                "position"   => lock!(searcher).position(&mut params),
-                "stop"       => should_stop.store(true, Ordering::Relaxed),
-                "quit"       => return,
-                "perft"      => perft(&lock!(searcher).root, &mut params),
-                "test"       => run(&mut lock!(searcher), params.next()),
-                "go"         => {
-                    lock!(searcher).timer.replace(&mut params);
-
-                    let searcher = searcher.clone();
-                    thread::spawn(move || {
-                        lock!(searcher).go();
-                    });
+                "uci"     => uci(),
+                "isready" => println!("readyok"),
+                "stop"    => engine.stop(),
+                "go"      => engine.go(&mut params),
+                "quit"    => {
+                    engine.stop();
+                    return
                 },
-                _ => println!("Unknown command: {}", first_word)
+                _ => engine.execute(&line)
             }
         }
     }
+    engine.stop();
+}
+
+/// Run a command which requires exclusive access to the searcher.
+/// An invalid command is reported to the GUI and otherwise ignored
+pub fn execute(searcher: &mut Searcher, line: &str) {
+    let mut params = line.split_whitespace();
+
+    if let Some(first_word) = params.next() {
+        let result = match first_word {
+            "setoption"  => searcher.update_settings(&mut params),
+            "position"   => searcher.position(&mut params),
+            "ucinewgame" => { searcher.reset(); Ok(()) },
+            "perft"      => { perft(&searcher.root, &mut params); Ok(()) },
+            "test"       => run(searcher, &mut params),
+            _ => { println!("Unknown command: {}", first_word); Ok(()) }
+        };
+
+        if let Err(e) = result { println!("info string {}", e) }
+    }
 }
 
 #[derive(Copy, Clone)]
 pub struct EngineSettings {
-    pub table_size: usize
+    pub table_size: usize,
+    pub multi_pv: usize,
+    pub threads: usize,
+    pub own_book: bool,
+    pub book_best: bool,
+    pub recapture_extension: bool,
+    pub passed_pawn_extension: bool,
+    pub quiescence_checks: bool
 }
 
 impl Default for EngineSettings {
     fn default() -> Self {
         EngineSettings {
-            table_size: 10_000_000
+            table_size: 10_000_000,
+            multi_pv: 1,
+            threads: 1,
+            own_book: false,
+            book_best: false,
+            recapture_extension: false,
+            passed_pawn_extension: false,
+            quiescence_checks: false
         }
     }
 }
 
-pub fn run(searcher: &mut Searcher, test: Option<&str>) {
-    match test {
-        Some("perf") => positions("testing/positions/performance",
-                searcher, &mut |s| s.go()),
-        Some("move") => positions("testing/positions/perftsuite.epd",
-                searcher, &mut |s| println!("{}", s.root.perft(6, true))),
-        _ => println!("Error: Valid options are `perf` or `move`")
+pub fn run(searcher: &mut Searcher, params: &mut Params) -> Result<(), ParseError> {
+    match params.next() {
+        Some("perf") => positions("testing/positions/performance", 10, searcher, &mut |s, _| {
+            s.timer.replace(&mut "wtime 10000 btime 10000 movestogo 1".split_whitespace()).unwrap();
+            s.go();
+        }),
+        Some("move") => epd::perft_suite("testing/positions/perftsuite.epd",
+                parse_or(params.next(), 6), searcher),
+        Some("epd") => {
+            let path = try!(params.next().ok_or(ParseError::Missing("test suite")));
+            try!(epd::run(path, params, searcher));
+        },
+        _ => println!("Error: Valid options are `perf`, `move [depth]` or `epd <file>`")
     };
+    Ok(())
 }
 
+/// Run perft split across a number of threads, with an optional table of the given size in MB.
+/// Alternatively, `perft stats` counts each kind of move at the last ply
 pub fn perft(board: &Board, params: &mut Params) {
-    let depth = parse_or(params.next(), 5);
+    let first = params.next();
+    if first == Some("stats") {
+        let depth = parse_or(params.next(), 5);
+        println!("{}", perft::stats(board, depth));
+        return
+    }
+
+    let depth = parse_or(first, 5);
+    let threads = max(1, parse_or(params.next(), 1));
+    let hash_mb = parse_or(params.next(), 0);
+
+    if depth == 0 {
+        println!("total = 1\n");
+        return
+    }
+
+    let table = if hash_mb > 0 { Some(Arc::new(PerftTable::new(hash_mb))) } else { None };
+    let start = time::precise_time_s();
+    let counts = perft::divide(board, depth, threads, table);
+    let elapsed = time::precise_time_s() - start;
+
+    for &(mv, count) in &counts {
+        println!("{}: {}", mv, count);
+    }
 
-    println!("total = {}\n", board.perft(depth, true));
+    let total: usize = counts.iter().map(|&(_, count)| count).sum();
+    println!("time {:.3} seconds, {:.0} nodes per second", elapsed, total as f64 / elapsed);
+    println!("total = {}\n", total);
 }
 
-pub fn positions(path: &str, searcher: &mut Searcher, do_work: &mut FnMut(&mut Searcher)) {
+/// Set up the searcher with each of the first `count` positions of an EPD file, and do some work
+/// with it. The work is also given the position's operations
+pub fn positions(path: &str, count: usize, searcher: &mut Searcher, do_work: &mut FnMut(&mut Searcher, &Epd)) {
     let file = match File::open(path) {
         Ok(file) => BufReader::new(file),
-        Err(e)   => panic!("Test suite {} could not be read. {:?}", path, e)
+        Err(e)   => {
+            println!("info string Test suite {} could not be read. {}", path, e);
+            return
+        }
     };
 
     let start = time::precise_time_s();
-    searcher.timer.replace(&mut "wtime 10000 btime 10000 movestogo 1".split_whitespace());
 
-    for line in file.lines().take(10) {
-        let fen = String::from("fen ") + &line.unwrap();
-        println!("{}", fen);
+    let mut remaining = count;
 
-        searcher.position(&mut fen.split_whitespace());
-        do_work(searcher);
+    for line in file.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                println!("info string Test suite {} could not be read. {}", path, e);
+                break
+            }
+        };
+        if line.trim().is_empty() { continue }
+        if remaining == 0 { break }
+        remaining -= 1;
+
+        println!("{}", line);
+
+        match Epd::parse(&line) {
+            Ok(epd) => {
+                searcher.set_position(epd.board, vec![epd.board.hash]);
+                do_work(searcher, &epd);
+            },
+            Err(e) => println!("info string {}", e)
+        }
     }
     println!("Time taken = {} seconds", time::precise_time_s() - start);
 }
 
+static INIT: Once = ONCE_INIT;
+
+/// Fill the move and hash tables. These are only written here, guarded so that it happens once,
+/// after which they are read only and may be shared by every search thread
 pub fn init() {
-    unsafe {
+    INIT.call_once(|| unsafe {
         magics::init();
         table::init();
-    }
+    });
 }
 
 pub fn uci() {
     println!("id name {}", ENGINE_NAME);
     println!("id author Alex Johnson");
     println!("option name Hash type spin min 1 max {} default 128", 1024 * 1024);
+    println!("option name MultiPV type spin min 1 max 500 default 1");
+    println!("option name Threads type spin min 1 max 128 default 1");
+    println!("option name OwnBook type check default false");
+    println!("option name BookFile type string default <empty>");
+    println!("option name BookBestMove type check default false");
+    println!("option name SyzygyPath type string default <empty>");
+    println!("option name RecaptureExtension type check default false");
+    println!("option name PassedPawnExtension type check default false");
+    println!("option name QuiescenceChecks type check default false");
     println!("uciok");
 }
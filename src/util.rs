@@ -1,4 +1,5 @@
 use std::str::FromStr;
+use types::Params;
 
 pub const ROW_1: u64 = 0xFF;
 pub const ROW_2: u64 = ROW_1 << 8;
@@ -103,20 +104,28 @@ pub fn for_all(mut pieces: u64, do_work: &mut FnMut(u32)) {
     }
 }
 
-macro_rules! lock {
-    ($e:expr) => ($e.lock().unwrap());
-}
-
-pub fn parse<T: FromStr>(p: Option<&str>) -> T {
-    p.and_then(|t| t.parse().ok()).expect(&format!("Could not parse {:?}", p))
+/// The ways in which a command from the GUI can be malformed. Each holds the offending word
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Missing(&'static str),
+    InvalidValue(String),
+    InvalidBoard(String),
+    InvalidSide(String),
+    InvalidCastling(String),
+    InvalidEnPassant(String),
+    InvalidMove(String),
+    IllegalMove(String),
+    AmbiguousMove(String),
+    InvalidPgn(String)
+}
+
+pub fn parse<T: FromStr>(p: Option<&str>) -> Result<T, ParseError> {
+    match p {
+        Some(word) => word.parse().map_err(|_| ParseError::InvalidValue(word.into())),
+        None => Err(ParseError::Missing("value"))
+    }
 }
 
 pub fn parse_or<T: FromStr>(p: Option<&str>, def: T) -> T {
     p.and_then(|t| t.parse().ok()).unwrap_or(def)
 }
-
-pub fn try_parse<T: FromStr>(p: Option<&str>) -> Result<T, String> {
-    p.ok_or("Value is None".into()).and_then(|t| t.parse().map_err(|_|
-        format!("{} cannot be parsed", t)
-    ))
-}